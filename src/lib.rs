@@ -1,193 +1,914 @@
 pub mod utils;
 
-use std::{collections::HashMap, error::Error, fmt::Debug, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    marker::PhantomData,
+    sync::Arc,
+};
 
-use parking_lot::Mutex;
-use serde_json::{Map, Value};
 use utils::{
-    parallelization::{split_and_run_first_row, split_and_run_next_row},
-    storage::{_from_disk_inner, _store_to_disk_inner},
-    tree_utils::{idx_to_binary_pos, inner_from_leaf_nodes_vr, pad_leaf_nodes_vr, proof_pos},
+    errors::{MerkleError, MAX_TREE_DEPTH},
+    hashers::{MerkleHasher, MerkleNode, PedersenHasher},
+    history::{node_before, VersionRecord},
+    node_store::{NodeStore, TreeUpdate},
+    parallelization::{compute_parent_row, TreeConfig, UpdatedNode},
+    preimage::Preimage,
+    proofs::{BatchMerkleProof, MerkleProof, UpdateWitness},
+    storage::{
+        _from_disk_inner, _stage_to_disk_inner, _store_delta_to_disk_inner, _store_to_disk_inner,
+        read_history, StorageConfig, TreeHeader,
+    },
+    tree_utils::{batch_proof_pos, idx_to_binary_pos, proof_pos, root_from_sparse_leaves_vr},
 };
 
-use crate::utils::tree_utils::get_zero_hash;
+use crate::utils::tree_utils::zero_hashes;
+
+/// (leaf_nodes, inner_nodes) of a tree
+type TreeNodes<N> = (HashMap<u64, N>, Vec<HashMap<u64, N>>);
 
 #[derive(Debug, Clone)]
-pub struct Tree {
-    pub leaf_nodes: Vec<String>,
-    pub inner_nodes: Vec<Vec<String>>,
+pub struct Tree<H: MerkleHasher = PedersenHasher> {
+    pub leaf_nodes: HashMap<u64, H::Node>, // only leaves that differ from the zero hash are stored
+    pub inner_nodes: Vec<HashMap<u64, H::Node>>, // inner_nodes[i - 1] holds the non-zero nodes at level i
     pub depth: u32,
-    pub root: String,
+    pub root: H::Node,
     pub shift: u32, // in case of a root tree we can start at a different depth
+    pub zero_hashes: Arc<Vec<H::Node>>, // zero hashes of levels 0..=depth + shift
+    pub store: Option<Arc<dyn NodeStore<H::Node>>>, // nodes that are not in memory are read from the store (lazy loading)
+    pub storage: Option<StorageConfig>, // where the tree was read from or written to on disk, its history is read from there
+    pub tree_index: u32,                // the index of the tree in storage
+    pub dirty_nodes: HashSet<(u32, u64)>, // (level, idx) of the nodes updated since the tree was last persisted
+    pub version: u64, // the number of batches applied to the tree (see `get_proof_at`)
+    pub history: Vec<VersionRecord<H::Node>>, // records of the versions that were not written to storage yet (see `TreeConfig::with_history_limit`)
+    pub batch_prev_nodes: HashMap<(u32, u64), H::Node>, // the nodes overwritten by the current batch
+    pub config: TreeConfig,                             // how the batch updates are parallelized
+    pub hasher: PhantomData<H>,
 }
 
 impl Tree {
-    pub fn new(depth: u32, shift: u32) -> Tree {
-        let leaf_nodes: Vec<String> = Vec::new();
-        let mut inner_nodes: Vec<Vec<String>> = Vec::new();
-        let root = get_zero_hash(depth, shift);
+    /// Creates an empty tree hashed with pedersen.
+    pub fn new(depth: u32, shift: u32) -> Result<Tree, MerkleError> {
+        Tree::with_hasher(depth, shift)
+    }
+}
+
+impl<H: MerkleHasher> Tree<H> {
+    /// Creates an empty tree hashed with the hash function `H`.
+    pub fn with_hasher(depth: u32, shift: u32) -> Result<Tree<H>, MerkleError> {
+        if depth > MAX_TREE_DEPTH {
+            return Err(MerkleError::DepthOverflow {
+                depth,
+                max_depth: MAX_TREE_DEPTH,
+            });
+        }
+
+        let leaf_nodes: HashMap<u64, H::Node> = HashMap::new();
+        let mut inner_nodes: Vec<HashMap<u64, H::Node>> = Vec::new();
+        let zero_hashes = zero_hashes::<H>(depth.saturating_add(shift))?;
+        let root = zero_hashes[(depth + shift) as usize];
 
         for _ in 0..depth {
-            let empty_vec: Vec<String> = Vec::new();
-            inner_nodes.push(empty_vec);
+            let empty_map: HashMap<u64, H::Node> = HashMap::new();
+            inner_nodes.push(empty_map);
         }
 
-        return Tree {
+        return Ok(Tree {
             leaf_nodes,
             inner_nodes,
             depth,
             root,
             shift,
-        };
+            zero_hashes,
+            store: None,
+            storage: None,
+            tree_index: 0,
+            dirty_nodes: HashSet::new(),
+            version: 0,
+            history: Vec::new(),
+            batch_prev_nodes: HashMap::new(),
+            config: TreeConfig::default(),
+            hasher: PhantomData,
+        });
+    }
+
+    /// Sets how the batch updates of the tree are parallelized (see `TreeConfig`).
+    pub fn with_config(mut self, config: TreeConfig) -> Tree<H> {
+        self.config = config;
+        self
+    }
+
+    /// Opens the tree at `tree_index` in the node store without loading any nodes.
+    ///
+    /// Nodes are read from the store when they are first needed, the updated nodes are kept
+    /// in memory until the tree is written back with `persist`.
+    pub fn from_store(
+        store: Arc<dyn NodeStore<H::Node>>,
+        tree_index: u32,
+        depth: u32,
+        shift: u32,
+    ) -> Result<Tree<H>, MerkleError> {
+        let mut tree = Tree::with_hasher(depth, shift)?;
+
+        if let Some(root) = store.read_root(tree_index)? {
+            // ? The stored root has to be the stored top node of the tree
+            let top_node = store.read_node(tree_index, depth, 0)?;
+            if top_node.unwrap_or(tree.zero_hash(depth)) != root {
+                return Err(MerkleError::Corrupted(format!(
+                    "the root of tree {} does not match its nodes",
+                    tree_index
+                )));
+            }
+
+            tree.root = root;
+        }
+        tree.version = store.read_version(tree_index)?;
+        tree.store = Some(store);
+        tree.tree_index = tree_index;
+
+        return Ok(tree);
     }
 
     // -----------------------------------------------------------------
     // Optimized parallel transition from one tx_batch to another
     // Updates the tree with a batch of updates and generates the preimage multi update proofs
     ///
+    /// Every call produces a new version of the tree (see `get_proof_at`).
+    ///
+    /// The nodes are updated level by level in ascending index order, so the updated nodes and the preimage
+    /// only depend on the updates and not on the iteration order of the hashmap.
+    ///
     /// # Arguments
     ///
     /// * `updated_hashes` - The hashmap of all the leaf nodes that need to be updated {idx: new_hash}
-    /// * `preimage` - the json_map to be filed with the preimage hashes
+    /// * `preimage` - the map to be filled with the preimage hashes {hash: [left, right]}
     pub fn batch_transition_updates(
         &mut self,
-        updated_hashes: &HashMap<u64, String>,
-        preimage: &mut Map<String, Value>,
-    ) {
+        updated_hashes: &HashMap<u64, H::Node>,
+        preimage: &mut Preimage<H::Node>,
+    ) -> Result<(), MerkleError> {
         //
 
-        if updated_hashes.len() == 0 {
-            return;
+        let mut updates: Vec<(u64, H::Node)> = updated_hashes
+            .iter()
+            .map(|(idx, hash)| (*idx, *hash))
+            .collect();
+        updates.sort_unstable_by_key(|(idx, _)| *idx);
+
+        self.sorted_transition_updates(updates, preimage)
+    }
+
+    /// Same as `batch_transition_updates` with the updated leaves sorted by index (without duplicates).
+    pub(crate) fn sorted_transition_updates(
+        &mut self,
+        updates: Vec<(u64, H::Node)>,
+        preimage: &mut Preimage<H::Node>,
+    ) -> Result<(), MerkleError> {
+        // ? Reject the whole batch before touching the tree if any index does not fit
+        for (idx, _) in updates.iter() {
+            self.check_node_idx(0, *idx)?;
+        }
+
+        let prev_root = self.root;
+        self.batch_prev_nodes.clear();
+
+        if updates.len() == 0 {
+            return self.push_version(prev_root);
         }
 
-        let tree_depth = self.depth;
+        // ? The updated nodes of every level sorted by index, all of them are hashed before the first
+        // ? node is set so that a failing read of the node store leaves the tree as it was
+        let mut rows: Vec<Vec<(u64, H::Node)>> = vec![updates];
 
-        let tree_mutex = Arc::new(Mutex::new(self));
-        let preimage_mutex = Arc::new(Mutex::new(preimage));
+        for level in 0..self.depth {
+            // ? The parents are hashed in parallel from the previous state of the tree
+            let parents = compute_parent_row(self, level, &rows[level as usize], true)?;
 
-        let mut next_row = split_and_run_first_row(&tree_mutex, &preimage_mutex, updated_hashes, 0);
+            for (_, hash, children, prev) in parents.iter() {
+                if let Some((prev_hash, prev_children)) = prev {
+                    preimage.insert_if_absent(*prev_hash, *prev_children);
+                }
+                preimage.insert(*hash, *children);
+            }
 
-        for i in 1..tree_depth as usize {
-            next_row = split_and_run_next_row(&tree_mutex, &preimage_mutex, &next_row, i, 0);
+            rows.push(
+                parents
+                    .into_iter()
+                    .map(|(parent, hash, _, _)| (parent, hash))
+                    .collect(),
+            );
         }
 
-        let mut tree = tree_mutex.lock();
-        tree.root = tree.inner_nodes[tree_depth as usize - 1][0].clone();
-        drop(tree);
+        for (level, row) in rows.iter().enumerate() {
+            for (idx, hash) in row.iter() {
+                if let Err(err) = self.set_node(level as u32, *idx, *hash) {
+                    self.restore_batch_prev_nodes();
+                    return Err(err);
+                }
+            }
+        }
+        self.root = rows[self.depth as usize][0].1;
+
+        self.push_version(prev_root)
     }
 
-    // -----------------------------------------------------------------
-    // HELPERS
+    /// Writes the nodes the current batch overwrote back into the tree (after the batch failed)
+    fn restore_batch_prev_nodes(&mut self) {
+        for ((level, idx), node) in self.batch_prev_nodes.drain() {
+            let level_nodes = if level == 0 {
+                &mut self.leaf_nodes
+            } else {
+                &mut self.inner_nodes[level as usize - 1]
+            };
 
-    fn update_leaf_node(&mut self, leaf_hash: &String, idx: u64) {
-        assert!(idx < 2_u64.pow(self.depth), "idx is greater than tree size");
+            if node == self.zero_hashes[(level + self.shift) as usize] && self.store.is_none() {
+                level_nodes.remove(&idx);
+            } else {
+                level_nodes.insert(idx, node);
+            }
+        }
+    }
 
-        if self.leaf_nodes.len() > idx as usize {
-            self.leaf_nodes[idx as usize] = leaf_hash.clone();
-        } else {
-            let len_diff = idx as usize - self.leaf_nodes.len();
+    /// Same as `batch_transition_updates` but also returns a witness for every update (in the order of `updates`),
+    /// as if the leaves were updated one after the other.
+    ///
+    /// A leaf can be updated several times, the tree ends up with its last value.
+    pub fn batch_transition_witnesses(
+        &mut self,
+        updates: &[(u64, H::Node)],
+        preimage: &mut Preimage<H::Node>,
+    ) -> Result<Vec<UpdateWitness<H>>, MerkleError> {
+        for (idx, _) in updates.iter() {
+            self.check_node_idx(0, *idx)?;
+        }
+
+        // ? The nodes changed by the updates before the current one
+        let mut updated_nodes: HashMap<(u32, u64), H::Node> = HashMap::new();
+        let mut witnesses: Vec<UpdateWitness<H>> = Vec::with_capacity(updates.len());
+
+        for (leaf_idx, new_leaf) in updates.iter() {
+            let node = |level: u32, idx: u64| match updated_nodes.get(&(level, idx)) {
+                Some(node) => Ok(*node),
+                None => self.node(level, idx),
+            };
 
-            for _ in 0..len_diff {
-                self.leaf_nodes.push("0".to_string());
+            let old_leaf = node(0, *leaf_idx)?;
+            let path = (0..self.depth)
+                .map(|level| node(level, (leaf_idx >> level) ^ 1))
+                .collect::<Result<Vec<H::Node>, MerkleError>>()?;
+
+            let mut hash = *new_leaf;
+            updated_nodes.insert((0, *leaf_idx), hash);
+            for (level, sibling) in path.iter().enumerate() {
+                hash = if (leaf_idx >> level) & 1 == 0 {
+                    H::hash(&hash, sibling)
+                } else {
+                    H::hash(sibling, &hash)
+                };
+                let parent_idx = leaf_idx.checked_shr(level as u32 + 1).unwrap_or(0);
+                updated_nodes.insert((level as u32 + 1, parent_idx), hash);
             }
 
-            self.leaf_nodes.push(leaf_hash.clone())
+            witnesses.push(UpdateWitness {
+                leaf_idx: *leaf_idx,
+                old_leaf,
+                new_leaf: *new_leaf,
+                path,
+                new_root: hash,
+                hasher: PhantomData,
+            });
         }
+
+        let updated_hashes = updates.iter().copied().collect::<HashMap<u64, H::Node>>();
+        self.batch_transition_updates(&updated_hashes, preimage)?;
+
+        return Ok(witnesses);
+    }
+
+    /// Computes the root the tree would have after `batch_transition_updates` with the same updates,
+    /// without changing the tree (and fills the same preimage if one is given).
+    ///
+    /// # Arguments
+    ///
+    /// * `updated_hashes` - The hashmap of all the leaf nodes that would be updated {idx: new_hash}
+    /// * `preimage` - the map to be filled with the preimage hashes {hash: [left, right]}
+    pub fn compute_root_after(
+        &self,
+        updated_hashes: &HashMap<u64, String>,
+        preimage: Option<&mut Preimage<H::Node>>,
+    ) -> Result<H::Node, MerkleError> {
+        let updated_hashes = updated_hashes
+            .iter()
+            .map(|(idx, hash)| Ok((*idx, H::Node::from_str(hash)?)))
+            .collect::<Result<HashMap<u64, H::Node>, MerkleError>>()?;
+
+        self.root_after(&updated_hashes, preimage)
     }
 
-    fn update_inner_node(&mut self, i: u32, j: u64, value: String) {
-        assert!(i <= self.depth, "i is greater than depth");
-        assert!(j < 2_u64.pow(self.depth - i), "j is greater than 2^i");
+    /// Same as `compute_root_after` with the updated leaves as nodes.
+    pub fn root_after(
+        &self,
+        updated_hashes: &HashMap<u64, H::Node>,
+        preimage: Option<&mut Preimage<H::Node>>,
+    ) -> Result<H::Node, MerkleError> {
+        let mut updates: Vec<(u64, H::Node)> = updated_hashes
+            .iter()
+            .map(|(idx, hash)| (*idx, *hash))
+            .collect();
+        updates.sort_unstable_by_key(|(idx, _)| *idx);
 
-        if self.inner_nodes.get(i as usize - 1).unwrap().len() > j as usize {
-            self.inner_nodes[i as usize - 1][j as usize] = value;
-        } else {
-            let len_diff = j as usize - self.inner_nodes[i as usize - 1].len();
+        self.sorted_root_after(updates, preimage)
+    }
+
+    /// Same as `root_after` with the updated leaves sorted by index (without duplicates).
+    pub(crate) fn sorted_root_after(
+        &self,
+        updates: Vec<(u64, H::Node)>,
+        mut preimage: Option<&mut Preimage<H::Node>>,
+    ) -> Result<H::Node, MerkleError> {
+        for (idx, _) in updates.iter() {
+            self.check_node_idx(0, *idx)?;
+        }
+
+        if updates.len() == 0 {
+            return Ok(self.root);
+        }
+
+        // ? Only the updated nodes of the current level are kept, all others are read from the tree
+        let mut row = updates;
+
+        for level in 0..self.depth {
+            let parents: Vec<UpdatedNode<H::Node>> =
+                compute_parent_row(self, level, &row, preimage.is_some())?;
+
+            if let Some(preimage) = preimage.as_deref_mut() {
+                for (_, hash, children, prev) in parents.iter() {
+                    if let Some((prev_hash, prev_children)) = prev {
+                        preimage.insert_if_absent(*prev_hash, *prev_children);
+                    }
+                    preimage.insert(*hash, *children);
+                }
+            }
 
-            for _ in 0..len_diff {
-                self.inner_nodes[i as usize - 1].push(get_zero_hash(i, self.shift));
+            row = parents
+                .into_iter()
+                .map(|(parent, hash, _, _)| (parent, hash))
+                .collect();
+        }
+
+        return Ok(row[0].1);
+    }
+
+    /// Records the nodes overwritten by the batch as the next version of the tree.
+    ///
+    /// Past the history limit of the config a tree opened from a node store is persisted,
+    /// any other tree drops its oldest version. Without a history limit no version is recorded.
+    fn push_version(&mut self, prev_root: H::Node) -> Result<(), MerkleError> {
+        if self.config.history_limit == 0 {
+            self.version += 1;
+            self.history.clear();
+
+            return Ok(());
+        }
+
+        let zero_hashes = self.zero_hashes.clone();
+        let shift = self.shift;
+
+        let mut prev_nodes = self
+            .batch_prev_nodes
+            .drain()
+            .map(|((level, idx), node)| {
+                let node = Some(node).filter(|node| *node != zero_hashes[(level + shift) as usize]);
+                (level, idx, node)
+            })
+            .collect::<Vec<(u32, u64, Option<H::Node>)>>();
+        prev_nodes.sort_unstable_by_key(|(level, idx, _)| (*level, *idx));
+
+        self.version += 1;
+        self.history.push(VersionRecord {
+            version: self.version,
+            prev_root,
+            prev_nodes,
+        });
+
+        if self.history.len() > self.config.history_limit {
+            if self.store.is_some() {
+                return self.persist();
+            }
+
+            let dropped = self.history.len() - self.config.history_limit;
+            self.history.drain(..dropped);
+        }
+
+        Ok(())
+    }
+
+    /// Undoes the last `batch_transition_updates` call, restoring the leaves, inner nodes, root
+    /// and version the tree had before it (using the nodes the batch overwrote).
+    ///
+    /// Batches can be rolled back one after the other, as long as they were not persisted to a node store
+    /// or written to disk (and are still within the history limit of the config). Writing a tree opened
+    /// from a node store to disk keeps its batches until they are persisted.
+    pub fn rollback_last_batch(&mut self) -> Result<(), MerkleError> {
+        let record = match self.history.pop() {
+            Some(record) => record,
+            None => return Err(MerkleError::NoBatchToRollback),
+        };
+
+        // ? A failed rollback leaves the tree and its history as they were
+        self.batch_prev_nodes.clear();
+        for (level, idx, node) in record.prev_nodes.iter() {
+            let node = node.unwrap_or(self.zero_hash(*level));
+
+            if let Err(err) = self.set_node(*level, *idx, node) {
+                self.restore_batch_prev_nodes();
+                self.history.push(record);
+                return Err(err);
             }
+        }
+        self.batch_prev_nodes.clear();
+
+        self.root = record.prev_root;
+        self.version = record.version - 1;
+
+        Ok(())
+    }
+
+    // -----------------------------------------------------------------
+    // HELPERS
+
+    fn update_leaf_node(&mut self, leaf_hash: &H::Node, idx: u64) -> Result<(), MerkleError> {
+        self.check_node_idx(0, idx)?;
+
+        // ? The overwritten nodes are only needed for the history
+        if self.config.history_limit > 0 && !self.batch_prev_nodes.contains_key(&(0, idx)) {
+            let prev_leaf = self.nth_leaf_node(idx)?;
+            self.batch_prev_nodes.insert((0, idx), prev_leaf);
+        }
 
-            self.inner_nodes[i as usize - 1].push(value);
+        // ? Zero leaves are not stored (unless they have to shadow a leaf in the node store)
+        if *leaf_hash == self.zero_hash(0) && self.store.is_none() {
+            self.leaf_nodes.remove(&idx);
+        } else {
+            self.leaf_nodes.insert(idx, *leaf_hash);
         }
+        self.dirty_nodes.insert((0, idx));
+
+        Ok(())
     }
 
-    fn nth_leaf_node(&self, n: u64) -> String {
-        assert!(n < 2_u64.pow(self.depth), "n is bigger than tree size");
+    fn update_inner_node(&mut self, i: u32, j: u64, value: H::Node) -> Result<(), MerkleError> {
+        self.check_inner_idx(i, j)?;
 
-        if self.leaf_nodes.get(n as usize).is_some() {
-            return self.leaf_nodes[n as usize].clone();
+        // ? The overwritten nodes are only needed for the history
+        if self.config.history_limit > 0 && !self.batch_prev_nodes.contains_key(&(i, j)) {
+            let prev_node = self.ith_inner_node(i, j)?;
+            self.batch_prev_nodes.insert((i, j), prev_node);
+        }
+
+        // ? Roots of empty subtrees are not stored (unless they have to shadow a node in the node store)
+        if value == self.zero_hash(i) && self.store.is_none() {
+            self.inner_nodes[i as usize - 1].remove(&j);
         } else {
-            return get_zero_hash(0, self.shift);
+            self.inner_nodes[i as usize - 1].insert(j, value);
+        }
+        self.dirty_nodes.insert((i, j));
+
+        Ok(())
+    }
+
+    fn nth_leaf_node(&self, n: u64) -> Result<H::Node, MerkleError> {
+        self.check_node_idx(0, n)?;
+
+        match self.leaf_nodes.get(&n) {
+            Some(leaf) => Ok(*leaf),
+            None => self.stored_node(0, n),
         }
     }
 
-    fn ith_inner_node(&self, i: u32, j: u64) -> String {
+    fn ith_inner_node(&self, i: u32, j: u64) -> Result<H::Node, MerkleError> {
         // ? Checks if the inner note at that spot exists, else it returns the zero hash
 
-        assert!(i <= self.depth, "i is greater than depth");
-        assert!(j < 2_u64.pow(self.depth - i), "j is greater than 2^i");
+        self.check_inner_idx(i, j)?;
 
-        if self.inner_nodes.get(i as usize - 1).is_some()
-            && self.inner_nodes[i as usize - 1].get(j as usize).is_some()
-        {
-            let res = self.inner_nodes[i as usize - 1][j as usize].clone();
-            return res;
+        match self.inner_nodes[i as usize - 1].get(&j) {
+            Some(node) => Ok(*node),
+            None => self.stored_node(i, j),
+        }
+    }
+
+    /// Reads a node that is not in memory from the node store (the zero hash if it is not stored)
+    fn stored_node(&self, level: u32, idx: u64) -> Result<H::Node, MerkleError> {
+        if let Some(store) = &self.store {
+            if let Some(node) = store.read_node(self.tree_index, level, idx)? {
+                return Ok(node);
+            }
+        }
+
+        Ok(self.zero_hash(level))
+    }
+
+    /// All the non-zero nodes of a level, including the ones that are only in the node store
+    fn level_nodes(&self, level: u32) -> Result<HashMap<u64, H::Node>, MerkleError> {
+        let in_memory = if level == 0 {
+            &self.leaf_nodes
         } else {
-            let zero_hash = get_zero_hash(i, self.shift);
-            return zero_hash;
+            &self.inner_nodes[level as usize - 1]
+        };
+
+        let mut nodes = match &self.store {
+            Some(store) => store.read_level(self.tree_index, level)?,
+            None => HashMap::new(),
+        };
+        nodes.extend(in_memory.iter());
+
+        let zero_hash = self.zero_hash(level);
+        nodes.retain(|_, node| *node != zero_hash);
+
+        Ok(nodes)
+    }
+
+    /// Checks that `idx` is a valid node index at level `level` (0 are the leaves)
+    fn check_node_idx(&self, level: u32, idx: u64) -> Result<(), MerkleError> {
+        if level > self.depth {
+            return Err(MerkleError::LevelOutOfRange {
+                level,
+                depth: self.depth,
+            });
+        }
+
+        // ? A level has 2^(depth - level) nodes
+        let level_depth = self.depth - level;
+        if level_depth < 64 && idx >> level_depth != 0 {
+            return Err(MerkleError::IndexOutOfRange {
+                level,
+                idx,
+                depth: self.depth,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn check_inner_idx(&self, i: u32, j: u64) -> Result<(), MerkleError> {
+        if i == 0 {
+            return Err(MerkleError::LevelOutOfRange {
+                level: i,
+                depth: self.depth,
+            });
         }
+
+        self.check_node_idx(i, j)
+    }
+
+    /// The zero hash at level `i` of this tree (taking the shift into account)
+    fn zero_hash(&self, i: u32) -> H::Node {
+        self.zero_hashes[(i + self.shift) as usize]
     }
 
     // I/O Operations --------------------------------------------------
 
-    /// Stores the tree to disk. Tree index is the index of the tree in the storage folder.
-    pub fn store_to_disk(&self, tree_index: u32) -> Result<(), Box<dyn Error>> {
+    /// Stores the tree to disk. Tree index is the index of the tree in the storage folder of the config.
+    ///
+    /// Every node is written, so afterwards no node is dirty anymore (see `store_delta_to_disk`),
+    /// unless the tree was opened from a node store and its changes still have to be persisted there.
+    /// The history records are written next to the tree and the older versions are read from disk afterwards.
+    pub fn store_to_disk(
+        &mut self,
+        config: &StorageConfig,
+        tree_index: u32,
+    ) -> Result<(), MerkleError> {
+        let (leaf_nodes, inner_nodes) = self.all_nodes()?;
+
         _store_to_disk_inner(
-            &self.leaf_nodes,
-            &self.inner_nodes,
+            config,
+            &self.header(),
+            &leaf_nodes,
+            &inner_nodes,
+            &self.root,
+            tree_index,
+            Some((&self.history, self.version)),
+        )?;
+        self.written_to_disk(config, tree_index);
+
+        Ok(())
+    }
+
+    /// Writes the tree to disk as part of a batch without replacing the stored tree.
+    /// It only becomes visible after the batch is committed with `storage::commit_staged`.
+    pub fn stage_to_disk(
+        &self,
+        config: &StorageConfig,
+        tree_index: u32,
+    ) -> Result<(), MerkleError> {
+        let (leaf_nodes, inner_nodes) = self.all_nodes()?;
+
+        _stage_to_disk_inner(
+            config,
+            &self.header(),
+            &leaf_nodes,
+            &inner_nodes,
             &self.root,
-            self.depth,
             tree_index,
         )
     }
 
-    /// Fetches the tree stored on disk and reconstructs it.
-    pub fn from_disk(tree_index: u32, depth: u32, shift: u32) -> Result<Tree, Box<dyn Error>> {
-        _from_disk_inner(tree_index, depth, shift)
+    /// The format header the tree is stored with
+    fn header(&self) -> TreeHeader {
+        TreeHeader::new::<H>(self.depth, self.shift)
+    }
+
+    /// The nodes of all the levels (loading the ones that are only in the node store)
+    fn all_nodes(&self) -> Result<TreeNodes<H::Node>, MerkleError> {
+        if self.store.is_none() {
+            return Ok((self.leaf_nodes.clone(), self.inner_nodes.clone()));
+        }
+
+        let leaf_nodes = self.level_nodes(0)?;
+        let inner_nodes = (1..=self.depth)
+            .map(|i| self.level_nodes(i))
+            .collect::<Result<Vec<HashMap<u64, H::Node>>, MerkleError>>()?;
+
+        return Ok((leaf_nodes, inner_nodes));
+    }
+
+    /// The nodes updated since the tree was last persisted as (level, idx, node), sorted by level and index.
+    /// Nodes that were reset to the zero hash are `None` (removed from storage).
+    pub fn dirty_node_changes(&self) -> Vec<(u32, u64, Option<H::Node>)> {
+        let mut dirty_nodes = self
+            .dirty_nodes
+            .iter()
+            .copied()
+            .collect::<Vec<(u32, u64)>>();
+        dirty_nodes.sort_unstable();
+
+        return dirty_nodes
+            .into_iter()
+            .map(|(level, idx)| {
+                let level_nodes = if level == 0 {
+                    &self.leaf_nodes
+                } else {
+                    &self.inner_nodes[level as usize - 1]
+                };
+
+                let node = level_nodes
+                    .get(&idx)
+                    .filter(|node| **node != self.zero_hash(level));
+                (level, idx, node.copied())
+            })
+            .collect();
+    }
+
+    /// The nodes updated since the tree was last persisted as an update for the node store.
+    pub fn store_update(&self) -> TreeUpdate<H::Node> {
+        return TreeUpdate {
+            tree_index: self.tree_index,
+            depth: self.depth,
+            shift: self.shift,
+            hasher_id: H::ID,
+            root: self.root,
+            nodes: self.dirty_node_changes(),
+            version: self.version,
+            history: self.history.clone(),
+        };
+    }
+
+    /// Writes the nodes updated since the last time (and the history records of the new versions)
+    /// to the node store the tree was opened from.
+    pub fn persist(&mut self) -> Result<(), MerkleError> {
+        match &self.store {
+            Some(store) => store.write_trees(&[self.store_update()])?,
+            None => {
+                return Err(MerkleError::Storage(
+                    "the tree was not opened from a node store".to_string(),
+                ))
+            }
+        }
+        self.dirty_nodes.clear();
+        self.history.clear();
+
+        Ok(())
+    }
+
+    /// Appends only the nodes updated since the last time to the stored tree on disk
+    /// instead of rewriting the whole tree like `store_to_disk`.
+    ///
+    /// The delta only applies on top of the tree this tree was read from or last written to,
+    /// any other target gets the whole tree.
+    pub fn store_delta_to_disk(
+        &mut self,
+        config: &StorageConfig,
+        tree_index: u32,
+    ) -> Result<(), MerkleError> {
+        if self.store.is_some()
+            || self.storage.as_ref() != Some(config)
+            || self.tree_index != tree_index
+        {
+            return self.store_to_disk(config, tree_index);
+        }
+
+        _store_delta_to_disk_inner(
+            config,
+            &self.header(),
+            &self.dirty_node_changes(),
+            &self.root,
+            tree_index,
+            Some((&self.history, self.version)),
+        )?;
+        self.written_to_disk(config, tree_index);
+
+        Ok(())
+    }
+
+    /// Everything in memory is on disk now. A tree opened from a node store keeps its dirty nodes
+    /// and history records until they are persisted to the store (its history is read from the store).
+    fn written_to_disk(&mut self, config: &StorageConfig, tree_index: u32) {
+        if self.store.is_none() {
+            self.dirty_nodes.clear();
+            self.history.clear();
+            self.storage = Some(config.clone());
+            self.tree_index = tree_index;
+        }
+    }
+
+    /// Fetches the tree stored on disk and reconstructs it at its stored version
+    /// (the older versions are read from its history on disk).
+    ///
+    /// Fails if the tree was never stored, if it was stored with a different hasher, depth or shift,
+    /// or if the stored file is corrupted.
+    pub fn from_disk(
+        config: &StorageConfig,
+        tree_index: u32,
+        depth: u32,
+        shift: u32,
+    ) -> Result<Tree<H>, MerkleError> {
+        _from_disk_inner(config, tree_index, depth, shift)
     }
 
     // -----------------------------------------------------------------
 
     /// Get the merkle proof for a leaf node.
-    pub fn get_proof(&self, leaf_idx: u64) -> (Vec<String>, Vec<i8>) {
-        let proof_binary_pos = idx_to_binary_pos(leaf_idx, self.depth as usize);
+    pub fn get_proof(&self, leaf_idx: u64) -> Result<MerkleProof<H>, MerkleError> {
+        self.build_proof(leaf_idx, |level, idx| self.node(level, idx))
+    }
+
+    /// Get the merkle proof for a leaf node as it was at `version` (0 is the tree before the first batch).
+    ///
+    /// Old versions only keep the nodes their batch overwrote, all the other nodes are read from the latest tree.
+    /// They are only recorded with a history limit (see `TreeConfig::with_history_limit`).
+    pub fn get_proof_at(&self, version: u64, leaf_idx: u64) -> Result<MerkleProof<H>, MerkleError> {
+        let records = self.history_after(version)?;
+
+        self.build_proof(leaf_idx, |level, idx| {
+            match node_before(&records, level, idx) {
+                Some(node) => Ok(node.unwrap_or(self.zero_hash(level))),
+                None => self.node(level, idx),
+            }
+        })
+    }
+
+    /// The root of the tree at `version` (0 is the tree before the first batch).
+    pub fn root_at(&self, version: u64) -> Result<H::Node, MerkleError> {
+        let records = self.history_after(version)?;
+
+        return Ok(records.first().map_or(self.root, |record| record.prev_root));
+    }
 
-        let proof_pos = proof_pos(leaf_idx, self.depth as usize);
+    fn build_proof(
+        &self,
+        leaf_idx: u64,
+        node: impl Fn(u32, u64) -> Result<H::Node, MerkleError>,
+    ) -> Result<MerkleProof<H>, MerkleError> {
+        let proof_binary_pos = idx_to_binary_pos(leaf_idx, self.depth as usize)?;
 
-        let mut proof: Vec<String> = Vec::new();
-        proof.push(self.nth_leaf_node(proof_pos[0]));
+        let proof_pos = proof_pos(leaf_idx, self.depth as usize)?;
 
-        for i in 1..self.depth {
-            let proof_val = self.ith_inner_node(i, proof_pos[i as usize] as u64);
+        let mut proof: Vec<H::Node> = Vec::new();
+        for i in 0..self.depth {
+            let proof_val = node(i, proof_pos[i as usize])?;
 
             proof.push(proof_val);
         }
 
-        return (proof, proof_binary_pos);
+        return Ok(MerkleProof {
+            leaf_idx,
+            leaf_hash: node(0, leaf_idx)?,
+            path: proof,
+            directions: proof_binary_pos,
+            depth: self.depth,
+            shift: self.shift,
+            hasher: PhantomData,
+        });
+    }
+
+    /// The node at (level, idx) where level 0 are the leaves
+    fn node(&self, level: u32, idx: u64) -> Result<H::Node, MerkleError> {
+        if level == 0 {
+            self.nth_leaf_node(idx)
+        } else {
+            self.ith_inner_node(level, idx)
+        }
+    }
+
+    /// Sets the node at (level, idx) where level 0 are the leaves
+    fn set_node(&mut self, level: u32, idx: u64, value: H::Node) -> Result<(), MerkleError> {
+        if level == 0 {
+            self.update_leaf_node(&value, idx)
+        } else {
+            self.update_inner_node(level, idx, value)
+        }
+    }
+
+    /// The history records of all the versions after `version` (from storage and in memory), sorted by version
+    fn history_after(&self, version: u64) -> Result<Vec<VersionRecord<H::Node>>, MerkleError> {
+        if version > self.version {
+            return Err(MerkleError::VersionOutOfRange {
+                version,
+                latest: self.version,
+            });
+        }
+
+        let mut records = match (&self.store, &self.storage) {
+            (Some(store), _) => store.read_history(self.tree_index, version)?,
+            (None, Some(config)) => read_history(config, self.tree_index, version)?,
+            (None, None) => Vec::new(),
+        };
+        records.extend(
+            self.history
+                .iter()
+                .filter(|record| record.version > version)
+                .cloned(),
+        );
+
+        // ? Without a node store every batch is a version, so a missing record was dropped
+        if self.store.is_none() && records.len() as u64 != self.version - version {
+            return Err(MerkleError::VersionPruned {
+                version,
+                oldest: records
+                    .first()
+                    .map_or(self.version, |record| record.version - 1),
+            });
+        }
+
+        Ok(records)
+    }
+
+    /// Get a single merkle proof for multiple leaf nodes.
+    ///
+    /// Only the minimal set of sibling nodes needed to recompute the root is included.
+    pub fn get_batch_proof(
+        &self,
+        leaf_indices: &[u64],
+    ) -> Result<BatchMerkleProof<H>, MerkleError> {
+        let mut leaf_indices = leaf_indices.to_vec();
+        leaf_indices.sort_unstable();
+        leaf_indices.dedup();
+
+        // ? Same as `get_proof`, a tree of depth 0 has no leaf with a proof
+        if let (0, Some(idx)) = (self.depth, leaf_indices.first()) {
+            return Err(MerkleError::IndexOutOfRange {
+                level: 0,
+                idx: *idx,
+                depth: 0,
+            });
+        }
+
+        let leaves = leaf_indices
+            .iter()
+            .map(|idx| Ok((*idx, self.nth_leaf_node(*idx)?)))
+            .collect::<Result<Vec<(u64, H::Node)>, MerkleError>>()?;
+
+        let proof_pos = batch_proof_pos(&leaf_indices, self.depth as usize);
+
+        let mut siblings: Vec<H::Node> = Vec::new();
+        for (level, level_pos) in proof_pos.iter().enumerate() {
+            for pos in level_pos.iter() {
+                siblings.push(self.node(level as u32, *pos)?);
+            }
+        }
+
+        return Ok(BatchMerkleProof {
+            leaves,
+            siblings,
+            depth: self.depth,
+            shift: self.shift,
+            hasher: PhantomData,
+        });
     }
 
     // -----------------------------------------------------------------
 
     /// Testing function that hashes the tree from the leaf nodes and checks if the root is correct (non-optimized)
     pub fn verify_root(&self) -> bool {
-        let leaf_nodes = pad_leaf_nodes_vr(&self.leaf_nodes, self.depth as usize, "0".to_string());
-
-        let inner_nodes: Vec<Vec<String>> =
-            inner_from_leaf_nodes_vr(self.depth as usize, &leaf_nodes);
-        let root = inner_nodes[0][0].clone();
+        let leaf_nodes = match self.level_nodes(0) {
+            Ok(leaf_nodes) => leaf_nodes,
+            Err(_) => return false,
+        };
 
-        return self.root == root;
+        match root_from_sparse_leaves_vr::<H>(&leaf_nodes, self.depth, self.shift) {
+            Ok(root) => self.root == root,
+            Err(_) => false,
+        }
     }
 }
 
@@ -199,13 +920,99 @@ impl Tree {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use starknet_crypto::FieldElement;
+
+    use crate::{
+        utils::{
+            errors::MerkleError,
+            hashers::{
+                Hash256, KeccakHasher, MerkleHasher, MerkleNode, PedersenHasher, PoseidonHasher,
+                Sha256Hasher,
+            },
+            history::VersionRecord,
+            node_store::{FileNodeStore, NodeStore, SledNodeStore, TreeUpdate},
+            parallelization::{build_tree, TreeConfig},
+            preimage::{Preimage, PreimageIssue, TreeSide},
+            state_tansitions::{
+                compute_root_after_update, get_state_proof_at, split_hashmap, state_root_at,
+                update_trees, update_trees_in_store, update_trees_in_store_with_config,
+                update_trees_with_config,
+            },
+            storage::{
+                collect_garbage, mark_finalized, migrate_storage, restore_backup, JournalEntry,
+                Retention, StorageConfig,
+            },
+            tree_utils::{field_from_str, get_zero_hash, zero_hashes},
+        },
+        Tree,
+    };
+
+    /// A fresh storage folder in the temp dir for one test, removed again when it is dropped
+    struct TestStorage {
+        base_path: PathBuf,
+        config: StorageConfig,
+    }
+
+    impl TestStorage {
+        fn new(name: &str) -> TestStorage {
+            let base_path = std::env::temp_dir().join(format!("merkle_trees_test_{}", name));
+            let _ = std::fs::remove_dir_all(&base_path);
+            let config = StorageConfig::new(&base_path, "state_tree");
+
+            TestStorage { base_path, config }
+        }
+
+        /// Another namespace in the same folder
+        fn namespace(&self, namespace: impl Into<String>) -> StorageConfig {
+            StorageConfig::new(&self.base_path, namespace)
+        }
+
+        fn file_store<N: MerkleNode>(&self) -> Arc<dyn NodeStore<N>> {
+            Arc::new(FileNodeStore::new(self.config.clone()))
+        }
+
+        /// Opens the sled store, also right after the last handle of it was dropped
+        fn sled_store<N: MerkleNode>(&self) -> Arc<dyn NodeStore<N>> {
+            // ? sled only releases the lock of its files once its background threads are done
+            for _ in 0..100 {
+                if let Ok(store) = SledNodeStore::open(&self.config) {
+                    return Arc::new(store);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+
+            Arc::new(SledNodeStore::open(&self.config).unwrap())
+        }
+    }
+
+    impl Drop for TestStorage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.base_path);
+        }
+    }
 
-    use crate::Tree;
+    /// Runs one batch that sets the leaves at the given indices to the given values
+    fn apply_batch<H: MerkleHasher>(tree: &mut Tree<H>, leaves: &[(u64, u64)]) {
+        let updated_hashes: HashMap<u64, H::Node> = leaves
+            .iter()
+            .map(|(idx, value)| (*idx, H::Node::from(*value)))
+            .collect();
+        tree.batch_transition_updates(&updated_hashes, &mut Preimage::new())
+            .unwrap();
+    }
 
     #[test]
     fn test1() -> Result<(), Box<dyn std::error::Error>> {
-        // let mut tree = Tree::new(32, 0);
+        // let mut tree = Tree::new(32, 0).unwrap();
 
         // let mut updated_hashes = HashMap::new();
         // for i in (0..100_000).into_iter().step_by(4) {
@@ -220,4 +1027,1410 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_proof_verification() {
+        let mut tree = Tree::new(8, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        for i in [0_u64, 3, 4, 77, 200] {
+            updated_hashes.insert(i, FieldElement::from(i + 1));
+        }
+
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+
+        for i in [0_u64, 3, 4, 77, 200, 201, 255] {
+            let proof = tree.get_proof(i).unwrap();
+            assert!(proof.verify(&tree.root));
+        }
+
+        let mut proof = tree.get_proof(77).unwrap();
+        proof.leaf_hash = FieldElement::from(1234_u64);
+        assert!(!proof.verify(&tree.root));
+
+        // ? An index outside of the tree is rejected even if its low bits match
+        let mut proof = tree.get_proof(3).unwrap();
+        proof.leaf_idx = 3 + 256 * 7;
+        assert!(!proof.verify(&tree.root));
+    }
+
+    #[test]
+    fn test_batch_proof_verification() {
+        let mut tree = Tree::new(8, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        for i in [0_u64, 1, 3, 4, 77, 200] {
+            updated_hashes.insert(i, FieldElement::from(i + 1));
+        }
+
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+
+        let proof = tree.get_batch_proof(&[200, 0, 1, 3, 77, 255]).unwrap();
+        assert!(proof.verify(&tree.root));
+
+        // ? 0 and 1 are siblings and share every node above them
+        assert!(proof.siblings.len() < 6 * 8);
+
+        let mut bad_proof = proof.clone();
+        bad_proof.siblings.pop();
+        assert!(!bad_proof.verify(&tree.root));
+    }
+
+    fn check_hasher<H: MerkleHasher>() {
+        let mut tree = Tree::<H>::with_hasher(6, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        for i in [1_u64, 2, 3, 40, 63] {
+            updated_hashes.insert(i, H::Node::from(i * 7));
+        }
+
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+
+        assert!(tree.verify_root());
+        assert!(tree.get_proof(40).unwrap().verify(&tree.root));
+        assert!(tree
+            .get_batch_proof(&[1, 2, 63])
+            .unwrap()
+            .verify(&tree.root));
+    }
+
+    #[test]
+    fn test_hashers() {
+        check_hasher::<PoseidonHasher>();
+        check_hasher::<KeccakHasher>();
+        check_hasher::<Sha256Hasher>();
+
+        // ? The keccak nodes are the full keccak256 of the children, like
+        // ? keccak256(abi.encodePacked(left, right)) on L1 (even if it doesn't fit in a field element)
+        use sha3::{Digest, Keccak256};
+        let digest: [u8; 32] = Keccak256::new()
+            .chain_update(Hash256::from(1_u64).0)
+            .chain_update(Hash256::from(2_u64).0)
+            .finalize()
+            .into();
+        assert!(Hash256(digest).to_string().starts_with("0xe90b7bce"));
+        assert!(FieldElement::from_bytes_be(&digest).is_err());
+
+        let mut tree = Tree::<KeccakHasher>::with_hasher(1, 0).unwrap();
+        apply_batch(&mut tree, &[(0, 1), (1, 2)]);
+        assert_eq!(tree.root, Hash256(digest));
+        assert_eq!(
+            tree.root,
+            Hash256::from_str(&tree.root.to_string()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_zero_hashes() {
+        assert_eq!(
+            get_zero_hash::<PedersenHasher>(0, 0).unwrap(),
+            FieldElement::ZERO
+        );
+        assert_eq!(
+            get_zero_hash::<PedersenHasher>(1, 0).unwrap().to_string(),
+            "2089986280348253421170679821480865132823066470938446095505822317253594081284"
+        );
+        assert_eq!(
+            get_zero_hash::<PedersenHasher>(31, 32).unwrap().to_string(),
+            "782789488582197453756570607249782803464646337934052302582063579083846343149"
+        );
+
+        let hashes = zero_hashes::<PoseidonHasher>(251).unwrap();
+        assert_eq!(hashes.len(), 252);
+        assert_eq!(
+            hashes[251],
+            PoseidonHasher::hash(&hashes[250], &hashes[250])
+        );
+    }
+
+    #[test]
+    fn test_sparse_nodes() {
+        let mut tree = Tree::new(32, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(2_u64.pow(31), FieldElement::from(5_u64));
+        updated_hashes.insert(2_u64.pow(32) - 1, FieldElement::from(6_u64));
+
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+
+        assert_eq!(tree.leaf_nodes.len(), 2);
+        assert!(tree.inner_nodes.iter().all(|level| level.len() <= 2));
+        assert!(tree.verify_root());
+
+        // ? Resetting the leaves to zero removes every stored node again
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(2_u64.pow(31), FieldElement::ZERO);
+        updated_hashes.insert(2_u64.pow(32) - 1, FieldElement::ZERO);
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+
+        assert!(tree.leaf_nodes.is_empty());
+        assert!(tree.inner_nodes.iter().all(|level| level.is_empty()));
+        assert_eq!(tree.root, Tree::new(32, 0).unwrap().root);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(matches!(
+            Tree::new(65, 0),
+            Err(MerkleError::DepthOverflow { depth: 65, .. })
+        ));
+
+        let mut tree = Tree::new(8, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(3_u64, FieldElement::from(1_u64));
+        updated_hashes.insert(256_u64, FieldElement::from(2_u64));
+
+        // ? The whole batch is rejected and the tree is left untouched
+        let mut preimage = Preimage::new();
+        let res = tree.batch_transition_updates(&updated_hashes, &mut preimage);
+        assert!(matches!(
+            res,
+            Err(MerkleError::IndexOutOfRange { idx: 256, .. })
+        ));
+        assert!(tree.leaf_nodes.is_empty());
+
+        assert!(tree.get_proof(256).is_err());
+        assert!(field_from_str("not a number").is_err());
+
+        // ? A tree of depth 0 has no proofs
+        let tree = Tree::new(0, 0).unwrap();
+        assert!(matches!(
+            tree.get_proof(0),
+            Err(MerkleError::IndexOutOfRange { idx: 0, .. })
+        ));
+        assert!(matches!(
+            tree.get_batch_proof(&[0]),
+            Err(MerkleError::IndexOutOfRange { idx: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_storage_namespaces() {
+        let storage = TestStorage::new("storage_namespaces");
+
+        let spot_config = storage.namespace("spot");
+        let perp_config = storage.namespace("perp");
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "7".to_string());
+        updated_hashes.insert(300_u64, "9".to_string());
+        let (_, spot_root, _) =
+            update_trees::<PedersenHasher>(&spot_config, updated_hashes, 16, 8).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "8".to_string());
+        let (prev_perp_root, perp_root, _) =
+            update_trees::<PedersenHasher>(&perp_config, updated_hashes, 16, 8).unwrap();
+
+        // ? The perp trees start empty and don't touch the spot trees
+        assert_eq!(prev_perp_root, Tree::new(16, 0).unwrap().root.to_string());
+        let spot_tree = Tree::<PedersenHasher>::from_disk(&spot_config, u32::MAX, 8, 8).unwrap();
+        assert_eq!(spot_tree.root.to_string(), spot_root);
+
+        // ? Rolling back the second perp batch restores the first one
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(6_u64, "1".to_string());
+        update_trees::<PedersenHasher>(&perp_config, updated_hashes, 16, 8).unwrap();
+        restore_backup(&perp_config).unwrap();
+
+        let perp_tree = Tree::<PedersenHasher>::from_disk(&perp_config, u32::MAX, 8, 8).unwrap();
+        assert_eq!(perp_tree.root.to_string(), perp_root);
+    }
+
+    #[test]
+    fn test_node_stores() {
+        let storage = TestStorage::new("node_stores");
+        let config = storage.config.clone();
+        let file_store: Arc<dyn NodeStore> = storage.file_store();
+        let sled_store: Arc<dyn NodeStore> = storage.sled_store();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "7".to_string());
+        updated_hashes.insert(300_u64, "9".to_string());
+
+        let (_, file_root, _) =
+            update_trees_in_store::<PedersenHasher>(&file_store, updated_hashes.clone(), 16, 8)
+                .unwrap();
+        let (_, sled_root, _) =
+            update_trees_in_store::<PedersenHasher>(&sled_store, updated_hashes, 16, 8).unwrap();
+        assert_eq!(file_root, sled_root);
+
+        // ? Trees written through the filesystem store are read back by `Tree::from_disk`
+        let tree = Tree::<PedersenHasher>::from_disk(&config, 1, 8, 0).unwrap();
+        assert_eq!(tree.leaf_nodes.get(&44), Some(&FieldElement::from(9_u64)));
+
+        // ? Nothing is loaded until a node is needed
+        let mut tree = Tree::<PedersenHasher>::from_store(sled_store.clone(), 0, 8, 0).unwrap();
+        assert!(tree.leaf_nodes.is_empty());
+        assert!(tree.get_proof(5).unwrap().verify(&tree.root));
+
+        apply_batch(&mut tree, &[(5, 0), (6, 3)]);
+        tree.persist().unwrap();
+
+        tree = Tree::<PedersenHasher>::from_store(sled_store, 0, 8, 0).unwrap();
+        assert!(tree.verify_root());
+        assert_eq!(tree.get_proof(5).unwrap().leaf_hash, FieldElement::ZERO);
+
+        // ? Writing the tree to disk does not drop the changes the store is still missing
+        apply_batch(&mut tree, &[(7, 4)]);
+        tree.store_to_disk(&storage.namespace("copy"), 0).unwrap();
+        apply_batch(&mut tree, &[(8, 2)]);
+        tree.persist().unwrap();
+        let root = tree.root;
+        drop(tree);
+
+        let tree = Tree::<PedersenHasher>::from_store(storage.sled_store(), 0, 8, 0).unwrap();
+        assert_eq!(tree.root, root);
+        assert!(tree.verify_root());
+    }
+
+    #[test]
+    fn test_pending_journal() {
+        let storage = TestStorage::new("pending_journal");
+        let config = storage.config.clone();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "7".to_string());
+        update_trees::<PedersenHasher>(&config, updated_hashes, 16, 8).unwrap();
+
+        // ? Stage the next batch of partition 0 and crash right after the journal is written
+        let store: Arc<dyn NodeStore> = storage.file_store();
+        let mut tree = Tree::<PedersenHasher>::from_store(store.clone(), 0, 8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(1));
+        apply_batch(&mut tree, &[(5, 9)]);
+        store.stage_trees(&[tree.store_update()]).unwrap();
+
+        let tree_dir = config.tree_dir();
+        let file_len = |name: &str| std::fs::metadata(tree_dir.join(name)).map_or(0, |m| m.len());
+        let entries = vec![
+            JournalEntry::Append {
+                tree_index: 0,
+                len: file_len("0.delta"),
+            },
+            JournalEntry::AppendHistory {
+                tree_index: 0,
+                len: file_len("0.history"),
+            },
+        ];
+        std::fs::write(
+            tree_dir.join("journal"),
+            bincode::serialize(&entries).unwrap(),
+        )
+        .unwrap();
+
+        // ? Every read path finishes the committed batch before reading
+        let reloaded = Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(reloaded.root, tree.root);
+        assert!(!tree_dir.join("journal").exists());
+
+        let store: Arc<dyn NodeStore> = storage.file_store();
+        assert_eq!(store.read_version(0).unwrap(), 2);
+        let reopened = Tree::<PedersenHasher>::from_store(store, 0, 8, 0).unwrap();
+        assert_eq!(reopened.root, tree.root);
+    }
+
+    #[test]
+    fn test_delta_persistence() {
+        let storage = TestStorage::new("delta_persistence");
+        let config = storage.config.clone();
+
+        let mut tree = Tree::new(16, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        for i in 0..500_u64 {
+            updated_hashes.insert(i * 7, FieldElement::from(i + 1));
+        }
+        tree.batch_transition_updates(&updated_hashes, &mut Preimage::new())
+            .unwrap();
+        tree.store_to_disk(&config, 0).unwrap();
+        assert!(tree.dirty_nodes.is_empty());
+
+        // ? Only the path of the updated leaf is dirty and written
+        apply_batch(&mut tree, &[(7, 0)]);
+        assert_eq!(tree.dirty_nodes.len(), 17);
+
+        tree.store_delta_to_disk(&config, 0).unwrap();
+        assert!(tree.dirty_nodes.is_empty());
+
+        let tree_len = std::fs::metadata(storage.base_path.join("state_tree/0"))
+            .unwrap()
+            .len();
+        let delta_len = std::fs::metadata(storage.base_path.join("state_tree/0.delta"))
+            .unwrap()
+            .len();
+        assert!(delta_len < tree_len / 10);
+
+        let stored_tree = Tree::<PedersenHasher>::from_disk(&config, 0, 16, 0).unwrap();
+        assert_eq!(stored_tree.root, tree.root);
+        assert_eq!(stored_tree.leaf_nodes, tree.leaf_nodes);
+        assert!(stored_tree.verify_root());
+
+        // ? A delta for another tree than the stored one is written as the whole tree
+        apply_batch(&mut tree, &[(14, 3)]);
+        tree.store_delta_to_disk(&config, 1).unwrap();
+        let stored_tree = Tree::<PedersenHasher>::from_disk(&config, 1, 16, 0).unwrap();
+        assert_eq!(stored_tree.root, tree.root);
+        assert!(stored_tree.verify_root());
+    }
+
+    #[test]
+    fn test_file_format() {
+        let storage = TestStorage::new("file_format");
+        let config = storage.config.clone();
+
+        let mut tree = Tree::new(8, 0).unwrap();
+        apply_batch(&mut tree, &[(3, 5), (200, 6)]);
+        tree.store_to_disk(&config, 0).unwrap();
+
+        assert!(matches!(
+            Tree::<PedersenHasher>::from_disk(&config, 1, 8, 0),
+            Err(MerkleError::TreeNotFound(1))
+        ));
+        assert!(matches!(
+            Tree::<PoseidonHasher>::from_disk(&config, 0, 8, 0),
+            Err(MerkleError::TreeMismatch(_))
+        ));
+
+        // ? A flipped bit or a truncated file is an error instead of a wrong root
+        let path = storage.base_path.join("state_tree/0");
+        let buf = std::fs::read(&path).unwrap();
+        let mut corrupted = buf.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(matches!(
+            Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0),
+            Err(MerkleError::Corrupted(_))
+        ));
+        std::fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        assert!(matches!(
+            Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0),
+            Err(MerkleError::Corrupted(_))
+        ));
+
+        // ? The original format has no header: dense levels of decimal strings, from the leaves
+        // ? up to the root. Such files are rejected until they are migrated
+        let dense_level = |level: u32| {
+            (0..1_u64 << (8 - level))
+                .map(|idx| tree.node(level, idx).unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+        let baseline: (Vec<String>, Vec<Vec<String>>, String, u32) = (
+            dense_level(0),
+            (1..=8).map(dense_level).collect(),
+            tree.root.to_string(),
+            8,
+        );
+        std::fs::write(&path, bincode::serialize(&baseline).unwrap()).unwrap();
+        std::fs::write(storage.base_path.join("state_tree/1"), []).unwrap();
+        assert!(matches!(
+            Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0),
+            Err(MerkleError::UnsupportedFormat(_))
+        ));
+
+        // ? A file that can't be decoded fails the migration before anything is rewritten
+        std::fs::write(storage.base_path.join("state_tree/2"), [1, 2, 3]).unwrap();
+        assert!(matches!(
+            migrate_storage::<PedersenHasher>(&config, 0),
+            Err(MerkleError::Corrupted(_))
+        ));
+        assert!(matches!(
+            Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0),
+            Err(MerkleError::UnsupportedFormat(_))
+        ));
+        assert!(storage.base_path.join("state_tree/1").exists());
+        std::fs::remove_file(storage.base_path.join("state_tree/2")).unwrap();
+
+        assert_eq!(
+            migrate_storage::<PedersenHasher>(&config, 0).unwrap(),
+            vec![0, 1]
+        );
+        let stored_tree = Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(stored_tree.root, tree.root);
+        assert_eq!(stored_tree.leaf_nodes, tree.leaf_nodes);
+        assert_eq!(stored_tree.inner_nodes, tree.inner_nodes);
+        assert!(!storage.base_path.join("state_tree/1").exists());
+        assert!(migrate_storage::<PedersenHasher>(&config, 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut tree = Tree::new(8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(4));
+
+        let mut snapshots = vec![tree.clone()];
+        for i in 0..3_u64 {
+            apply_batch(&mut tree, &[(i, i + 1), (7, 10 + i)]);
+
+            snapshots.push(tree.clone());
+        }
+        assert_eq!(tree.version, 3);
+
+        for (version, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(tree.root_at(version as u64).unwrap(), snapshot.root);
+            assert_eq!(
+                tree.get_proof_at(version as u64, 7).unwrap(),
+                snapshot.get_proof(7).unwrap()
+            );
+        }
+        assert!(matches!(
+            tree.root_at(4),
+            Err(MerkleError::VersionOutOfRange {
+                version: 4,
+                latest: 3
+            })
+        ));
+
+        let storage = TestStorage::new("versions");
+        let config = storage.config.clone();
+        let file_store: Arc<dyn NodeStore> = storage.file_store();
+        let sled_store: Arc<dyn NodeStore> = storage.sled_store();
+
+        for store in [file_store, sled_store] {
+            let mut roots: Vec<String> = Vec::new();
+            for i in 0..3_u64 {
+                let mut updated_hashes = HashMap::new();
+                updated_hashes.insert(300_u64, (i + 1).to_string());
+                updated_hashes.insert(i * 1000, "5".to_string());
+                let (prev_root, new_root, _) =
+                    update_trees_in_store::<PedersenHasher>(&store, updated_hashes, 16, 8).unwrap();
+
+                if i == 0 {
+                    roots.push(prev_root);
+                }
+                roots.push(new_root);
+            }
+            assert_eq!(store.read_version(u32::MAX).unwrap(), 3);
+
+            // ? The partition of leaf 0 was only touched by the first batch
+            for (version, root) in roots.iter().enumerate() {
+                let version = version as u64;
+                assert_eq!(
+                    &state_root_at::<PedersenHasher>(&store, version, 16, 8).unwrap(),
+                    root
+                );
+
+                let proof =
+                    get_state_proof_at::<PedersenHasher>(&store, version, 300, 16, 8).unwrap();
+                assert_eq!(proof.leaf_hash, FieldElement::from(version));
+                assert!(proof.verify(&field_from_str(root).unwrap()));
+
+                let proof =
+                    get_state_proof_at::<PedersenHasher>(&store, version, 0, 16, 8).unwrap();
+                assert!(proof.verify(&field_from_str(root).unwrap()));
+            }
+        }
+
+        // ? Restoring the backup also drops the history of the rolled back batch
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(300_u64, "9".to_string());
+        update_trees::<PedersenHasher>(&config, updated_hashes, 16, 8).unwrap();
+        assert_eq!(
+            NodeStore::<FieldElement>::read_version(&FileNodeStore::new(config.clone()), u32::MAX)
+                .unwrap(),
+            4
+        );
+
+        restore_backup(&config).unwrap();
+        assert_eq!(
+            NodeStore::<FieldElement>::read_version(&FileNodeStore::new(config.clone()), u32::MAX)
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_history_on_disk() {
+        let storage = TestStorage::new("history_on_disk");
+        let config = storage.config.clone();
+
+        let next_batch = |tree: &mut Tree<KeccakHasher>, i: u64| {
+            apply_batch(tree, &[(i, i + 1), (7, 10 + i)]);
+            tree.clone()
+        };
+
+        // ? Only the last two versions are kept in memory
+        let mut tree = Tree::<KeccakHasher>::with_hasher(8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(2));
+        let mut snapshots = vec![tree.clone()];
+        for i in 0..3_u64 {
+            snapshots.push(next_batch(&mut tree, i));
+        }
+        assert_eq!(tree.history.len(), 2);
+        assert!(matches!(
+            tree.root_at(0),
+            Err(MerkleError::VersionPruned {
+                version: 0,
+                oldest: 1
+            })
+        ));
+
+        // ? Writing the tree moves its history to disk, where the reloaded tree finds it
+        tree.store_to_disk(&config, 0).unwrap();
+        assert!(tree.history.is_empty());
+        let stored_tree = Tree::<KeccakHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(stored_tree.version, 3);
+        for version in 1..=3_u64 {
+            let snapshot = &snapshots[version as usize];
+            assert_eq!(tree.root_at(version).unwrap(), snapshot.root);
+            assert_eq!(stored_tree.root_at(version).unwrap(), snapshot.root);
+            assert_eq!(
+                stored_tree.get_proof_at(version, 7).unwrap(),
+                snapshot.get_proof(7).unwrap()
+            );
+        }
+
+        let mut stored_tree = stored_tree.with_config(TreeConfig::new().with_history_limit(4));
+        snapshots.push(next_batch(&mut stored_tree, 3));
+        stored_tree.store_delta_to_disk(&config, 0).unwrap();
+        let stored_tree = Tree::<KeccakHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(stored_tree.version, 4);
+        assert_eq!(stored_tree.root_at(1).unwrap(), snapshots[1].root);
+        assert_eq!(stored_tree.root_at(4).unwrap(), snapshots[4].root);
+
+        // ? A version dropped before it was written replaces the history on disk instead of leaving a gap
+        let mut stored_tree = stored_tree.with_config(TreeConfig::new().with_history_limit(2));
+        for i in 4..7_u64 {
+            snapshots.push(next_batch(&mut stored_tree, i));
+        }
+        stored_tree.store_delta_to_disk(&config, 0).unwrap();
+        let stored_tree = Tree::<KeccakHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(stored_tree.version, 7);
+        assert_eq!(stored_tree.root_at(5).unwrap(), snapshots[5].root);
+        assert_eq!(
+            stored_tree.get_proof_at(6, 7).unwrap(),
+            snapshots[6].get_proof(7).unwrap()
+        );
+        assert!(matches!(
+            stored_tree.root_at(4),
+            Err(MerkleError::VersionPruned {
+                version: 4,
+                oldest: 5
+            })
+        ));
+
+        // ? A tree opened from a node store is persisted instead of dropping versions
+        let store: Arc<dyn NodeStore<Hash256>> = storage.file_store();
+        let mut store_tree = Tree::<KeccakHasher>::from_store(store.clone(), 1, 8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(1));
+        next_batch(&mut store_tree, 0);
+        assert_eq!(store.read_version(1).unwrap(), 0);
+        next_batch(&mut store_tree, 1);
+        assert!(store_tree.history.is_empty() && store_tree.dirty_nodes.is_empty());
+        assert_eq!(store.read_version(1).unwrap(), 2);
+        assert_eq!(store_tree.root_at(0).unwrap(), snapshots[0].root);
+
+        // ? Writing it to disk keeps the versions the store does not have yet
+        let mut store_tree = Tree::<KeccakHasher>::from_store(store.clone(), 2, 8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(4));
+        next_batch(&mut store_tree, 0);
+        next_batch(&mut store_tree, 1);
+        store_tree
+            .store_to_disk(&storage.namespace("copy"), 0)
+            .unwrap();
+        assert_eq!(store_tree.root_at(0).unwrap(), snapshots[0].root);
+        assert_eq!(store_tree.root_at(1).unwrap(), snapshots[1].root);
+        store_tree.rollback_last_batch().unwrap();
+        assert_eq!(store_tree.root, snapshots[1].root);
+
+        // ? Another tree written in its place does not inherit its history
+        Tree::<KeccakHasher>::with_hasher(8, 0)
+            .unwrap()
+            .store_to_disk(&config, 0)
+            .unwrap();
+        let stored_tree = Tree::<KeccakHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(stored_tree.version, 0);
+        assert_eq!(stored_tree.root_at(0).unwrap(), snapshots[0].root);
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let storage = TestStorage::new("garbage_collection");
+        let config = storage.config.clone();
+
+        // ? The second batch resets partition 1 to all zero leaves
+        let mut roots: Vec<String> = Vec::new();
+        for updates in [
+            vec![(5_u64, "7"), (300, "9")],
+            vec![(300, "0")],
+            vec![(5, "8")],
+        ] {
+            let updated_hashes = updates
+                .into_iter()
+                .map(|(idx, hash)| (idx, hash.to_string()))
+                .collect::<HashMap<u64, String>>();
+            let (_, new_root, _) =
+                update_trees::<PedersenHasher>(&config, updated_hashes, 16, 8).unwrap();
+            roots.push(new_root);
+        }
+
+        let collection = collect_garbage::<PedersenHasher>(&config, Retention::Batches(2)).unwrap();
+        assert_eq!(collection.removed_backups, vec![1]);
+        assert_eq!(collection.removed_trees, vec![1]);
+        assert!(collection.bytes_reclaimed > 0);
+        assert!(!storage.base_path.join("state_tree/1").exists());
+
+        // ? The removed partition still reads as empty and keeps its history
+        let store: Arc<dyn NodeStore> = storage.file_store();
+        assert_eq!(
+            state_root_at::<PedersenHasher>(&store, 3, 16, 8).unwrap(),
+            roots[2]
+        );
+        let proof = get_state_proof_at::<PedersenHasher>(&store, 1, 300, 16, 8).unwrap();
+        assert!(proof.verify(&field_from_str(&roots[0]).unwrap()));
+
+        mark_finalized(&config, 3).unwrap();
+        let collection = collect_garbage::<PedersenHasher>(&config, Retention::Finalized).unwrap();
+        assert_eq!(collection.removed_backups, vec![2, 3]);
+        assert!(collection.removed_trees.is_empty());
+
+        // ? The staged files of a batch that is still being written are kept,
+        // ? the ones a crashed batch left behind are removed
+        let mut tree = Tree::<PedersenHasher>::from_store(store.clone(), 0, 8, 0).unwrap();
+        apply_batch(&mut tree, &[(5, 10)]);
+        store.stage_trees(&[tree.store_update()]).unwrap();
+        let orphan = config.tree_dir().join("7.staged");
+        std::fs::write(&orphan, b"crashed").unwrap();
+
+        collect_garbage::<PedersenHasher>(&config, Retention::Finalized).unwrap();
+        assert!(!orphan.exists());
+        store.commit_trees(&[0]).unwrap();
+        let reloaded = Tree::<PedersenHasher>::from_disk(&config, 0, 8, 0).unwrap();
+        assert_eq!(reloaded.root, tree.root);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut tree = Tree::new(8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(2));
+        let empty_tree = tree.clone();
+
+        let mut updated_hashes = HashMap::new();
+        for i in 0..20_u64 {
+            updated_hashes.insert(i * 3, FieldElement::from(i + 1));
+        }
+        tree.batch_transition_updates(&updated_hashes, &mut Preimage::new())
+            .unwrap();
+        let snapshot = tree.clone();
+
+        apply_batch(&mut tree, &[(3, 0), (4, 9), (255, 7)]);
+
+        // ? Every batch can be undone in turn
+        for expected in [&snapshot, &empty_tree] {
+            tree.rollback_last_batch().unwrap();
+
+            assert_eq!(tree.leaf_nodes, expected.leaf_nodes);
+            assert_eq!(tree.inner_nodes, expected.inner_nodes);
+            assert_eq!(tree.root, expected.root);
+            assert_eq!(tree.version, expected.version);
+        }
+        assert!(matches!(
+            tree.rollback_last_batch(),
+            Err(MerkleError::NoBatchToRollback)
+        ));
+
+        // ? Without a history limit no version is kept to roll back to
+        let mut tree = Tree::new(8, 0).unwrap();
+        apply_batch(&mut tree, &[(3, 5)]);
+        assert!(tree.batch_prev_nodes.is_empty() && tree.history.is_empty());
+        assert_eq!(tree.version, 1);
+        assert!(matches!(
+            tree.root_at(0),
+            Err(MerkleError::VersionPruned {
+                version: 0,
+                oldest: 1
+            })
+        ));
+        assert!(matches!(
+            tree.rollback_last_batch(),
+            Err(MerkleError::NoBatchToRollback)
+        ));
+    }
+
+    #[test]
+    fn test_failed_batch() {
+        let storage = TestStorage::new("failed_batch");
+
+        // ? A node store whose reads fail once `reads_left` is used up
+        #[derive(Debug)]
+        struct FailingStore {
+            store: Arc<dyn NodeStore<Hash256>>,
+            reads_left: AtomicUsize,
+        }
+
+        impl FailingStore {
+            fn read(&self) -> Result<(), MerkleError> {
+                match self
+                    .reads_left
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(MerkleError::Storage("injected read failure".to_string())),
+                }
+            }
+        }
+
+        impl NodeStore<Hash256> for FailingStore {
+            fn read_root(&self, tree_index: u32) -> Result<Option<Hash256>, MerkleError> {
+                self.store.read_root(tree_index)
+            }
+
+            fn read_node(
+                &self,
+                tree_index: u32,
+                level: u32,
+                idx: u64,
+            ) -> Result<Option<Hash256>, MerkleError> {
+                self.read()?;
+                self.store.read_node(tree_index, level, idx)
+            }
+
+            fn read_level(
+                &self,
+                tree_index: u32,
+                level: u32,
+            ) -> Result<HashMap<u64, Hash256>, MerkleError> {
+                self.read()?;
+                self.store.read_level(tree_index, level)
+            }
+
+            fn read_version(&self, tree_index: u32) -> Result<u64, MerkleError> {
+                self.store.read_version(tree_index)
+            }
+
+            fn read_history(
+                &self,
+                tree_index: u32,
+                from_version: u64,
+            ) -> Result<Vec<VersionRecord<Hash256>>, MerkleError> {
+                self.store.read_history(tree_index, from_version)
+            }
+
+            fn stage_trees(&self, updates: &[TreeUpdate<Hash256>]) -> Result<(), MerkleError> {
+                self.store.stage_trees(updates)
+            }
+
+            fn commit_trees(&self, tree_indices: &[u32]) -> Result<(), MerkleError> {
+                self.store.commit_trees(tree_indices)
+            }
+        }
+
+        let failing_store = Arc::new(FailingStore {
+            store: storage.file_store(),
+            reads_left: AtomicUsize::new(usize::MAX),
+        });
+        let store: Arc<dyn NodeStore<Hash256>> = failing_store.clone();
+        let mut tree = Tree::<KeccakHasher>::from_store(store, 0, 8, 0)
+            .unwrap()
+            .with_config(TreeConfig::new().with_history_limit(4));
+        apply_batch(&mut tree, &[(3, 1), (100, 2)]);
+        tree.persist().unwrap();
+        let persisted_root = tree.root;
+        apply_batch(&mut tree, &[(3, 4), (7, 5)]);
+
+        // ? Wherever the reads of the next batch fail, the tree is left as it was
+        let mut reads = 0;
+        loop {
+            let mut failed_tree = tree.clone();
+            failing_store.reads_left.store(reads, Ordering::SeqCst);
+            let updated_hashes = [(3, 0), (200, 6), (201, 7)]
+                .into_iter()
+                .map(|(idx, hash)| (idx, Hash256::from(hash as u64)))
+                .collect::<HashMap<u64, Hash256>>();
+            match failed_tree.batch_transition_updates(&updated_hashes, &mut Preimage::new()) {
+                Ok(()) => break,
+                Err(err) => assert!(matches!(err, MerkleError::Storage(_))),
+            }
+            failing_store.reads_left.store(usize::MAX, Ordering::SeqCst);
+
+            assert_eq!(failed_tree.root, tree.root);
+            assert_eq!(failed_tree.version, tree.version);
+            assert!(failed_tree.verify_root());
+            for idx in [3, 7, 100, 200, 201] {
+                assert_eq!(
+                    failed_tree.get_proof(idx).unwrap(),
+                    tree.get_proof(idx).unwrap()
+                );
+            }
+
+            // ? The last batch that went through is the one rolled back
+            failed_tree.rollback_last_batch().unwrap();
+            assert_eq!(failed_tree.root, persisted_root);
+            assert!(failed_tree.verify_root());
+
+            reads += 1;
+        }
+        assert!(reads > 0);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut tree = Tree::new(8, 0).unwrap();
+        let mut updated_hashes = HashMap::new();
+        for i in 0..30_u64 {
+            updated_hashes.insert(i * 5, FieldElement::from(i + 1));
+        }
+        tree.batch_transition_updates(&updated_hashes, &mut Preimage::new())
+            .unwrap();
+        let snapshot = tree.clone();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "0".to_string());
+        updated_hashes.insert(6_u64, "12".to_string());
+        updated_hashes.insert(200_u64, "13".to_string());
+
+        let mut dry_run_preimage = Preimage::new();
+        let root = tree
+            .compute_root_after(&updated_hashes, Some(&mut dry_run_preimage))
+            .unwrap();
+        assert_eq!(tree.leaf_nodes, snapshot.leaf_nodes);
+        assert_eq!(tree.inner_nodes, snapshot.inner_nodes);
+        assert_eq!(tree.root, snapshot.root);
+
+        let updated_hashes = updated_hashes
+            .iter()
+            .map(|(idx, hash)| (*idx, field_from_str(hash).unwrap()))
+            .collect::<HashMap<u64, FieldElement>>();
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+        assert_eq!(root, tree.root);
+        assert_eq!(dry_run_preimage, preimage);
+
+        let storage = TestStorage::new("dry_run");
+        let config = storage.config.clone();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "7".to_string());
+        updated_hashes.insert(300_u64, "9".to_string());
+        update_trees::<PedersenHasher>(&config, updated_hashes, 16, 8).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "0".to_string());
+        updated_hashes.insert(40_000_u64, "3".to_string());
+
+        let mut dry_run_preimage = Preimage::new();
+        let root = compute_root_after_update::<PedersenHasher>(
+            &config,
+            &updated_hashes,
+            16,
+            8,
+            Some(&mut dry_run_preimage),
+        )
+        .unwrap();
+        let (_, new_root, preimage) =
+            update_trees::<PedersenHasher>(&config, updated_hashes, 16, 8).unwrap();
+        assert_eq!(root, new_root);
+        assert_eq!(dry_run_preimage, preimage);
+    }
+
+    #[test]
+    fn test_preimage() {
+        let mut tree = Tree::new(8, 0).unwrap();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(3_u64, FieldElement::from(5_u64));
+        updated_hashes.insert(200_u64, FieldElement::from(6_u64));
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+        assert!(preimage.verify::<PedersenHasher>());
+        assert_eq!(preimage.get(&tree.root).unwrap().len(), 2);
+
+        // ? The json format is the same either way
+        let json = preimage.to_json();
+        assert_eq!(Preimage::from_json(&json).unwrap(), preimage);
+        assert_eq!(
+            serde_json::to_value(&preimage).unwrap(),
+            serde_json::Value::Object(json.clone())
+        );
+        let parsed: Preimage = serde_json::from_value(serde_json::Value::Object(json)).unwrap();
+        assert_eq!(parsed, preimage);
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(4_u64, FieldElement::from(7_u64));
+        let mut next_preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut next_preimage)
+            .unwrap();
+
+        let len = preimage.len();
+        preimage.merge(next_preimage);
+        assert!(preimage.len() > len);
+        assert!(preimage.contains(&tree.root));
+
+        preimage.insert(
+            FieldElement::from(1_u64),
+            [FieldElement::from(2_u64), FieldElement::from(3_u64)],
+        );
+        assert_eq!(
+            preimage.invalid_hashes::<PedersenHasher>(),
+            vec![FieldElement::from(1_u64)]
+        );
+    }
+
+    #[test]
+    fn test_deterministic_updates() {
+        let updates = (0..300_u64)
+            .map(|i| ((i * 7919) % 4096, Hash256::from(i + 1)))
+            .collect::<Vec<(u64, Hash256)>>();
+
+        let mut outputs = Vec::new();
+        for reverse in [false, true] {
+            let mut updated_hashes = HashMap::new();
+            let mut ordered = updates.clone();
+            if reverse {
+                ordered.reverse();
+            }
+            for (idx, hash) in ordered {
+                updated_hashes.insert(idx, hash);
+            }
+
+            let mut tree = Tree::<KeccakHasher>::with_hasher(12, 0).unwrap();
+            let mut preimage = Preimage::new();
+            tree.batch_transition_updates(&updated_hashes, &mut preimage)
+                .unwrap();
+
+            let entries = preimage
+                .iter()
+                .map(|(hash, children)| (*hash, *children))
+                .collect::<Vec<_>>();
+            outputs.push((entries, tree.store_update()));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+
+        // ? The same batch of the state tree gives byte-identical preimages
+        let mut preimages = Vec::new();
+        for run in 0..2 {
+            let storage = TestStorage::new(&format!("deterministic_{}", run));
+            let config = storage.config.clone();
+
+            let updated_state_hashes = updates[..4]
+                .iter()
+                .map(|(idx, hash)| (*idx, hash.to_string()))
+                .collect::<HashMap<u64, String>>();
+            let (_, new_root, preimage) =
+                update_trees::<KeccakHasher>(&config, updated_state_hashes, 12, 6).unwrap();
+
+            preimages.push((new_root, serde_json::to_string(&preimage).unwrap()));
+        }
+        assert_eq!(preimages[0], preimages[1]);
+    }
+
+    #[test]
+    fn test_tree_config() {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let configs = [
+            TreeConfig::default(),
+            TreeConfig::new().sequential(),
+            TreeConfig::new()
+                .with_chunk_size(3)
+                .with_sequential_threshold(0)
+                .with_thread_pool(Arc::new(thread_pool)),
+        ];
+        assert_eq!(configs[2].chunk_size, 4);
+
+        let leaves = (0..64_u64)
+            .map(|i| Hash256::from(i * 3 + 1))
+            .collect::<Vec<Hash256>>();
+        let updated_hashes = leaves
+            .iter()
+            .enumerate()
+            .map(|(idx, leaf)| (idx as u64, *leaf))
+            .collect::<HashMap<u64, Hash256>>();
+
+        let mut outputs = Vec::new();
+        for config in configs.iter() {
+            let mut tree = Tree::<KeccakHasher>::with_hasher(6, 0)
+                .unwrap()
+                .with_config(config.clone());
+            let mut preimage = Preimage::new();
+            tree.batch_transition_updates(&updated_hashes, &mut preimage)
+                .unwrap();
+
+            let root = build_tree::<KeccakHasher>(6, &leaves, 0, config).unwrap();
+            assert_eq!(root, tree.root);
+
+            outputs.push((tree.root, preimage));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+
+        // ? A tree of depth 0 is its leaf, and the leaves have to fit in the tree
+        let config = &configs[0];
+        assert_eq!(
+            build_tree::<KeccakHasher>(0, &leaves[..1].to_vec(), 0, config).unwrap(),
+            leaves[0]
+        );
+        assert!(matches!(
+            build_tree::<KeccakHasher>(0, &Vec::new(), 0, config),
+            Err(MerkleError::NoLeaves)
+        ));
+        assert!(matches!(
+            build_tree::<KeccakHasher>(5, &leaves, 0, config),
+            Err(MerkleError::IndexOutOfRange {
+                level: 0,
+                idx: 63,
+                depth: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn test_split_hashmap() {
+        let mut hashmap = HashMap::new();
+        hashmap.insert(1_u64 << 40, FieldElement::from(1_u64));
+        hashmap.insert(17, FieldElement::from(2_u64));
+        hashmap.insert(3, FieldElement::from(3_u64));
+        hashmap.insert(16, FieldElement::from(4_u64));
+
+        // ? Only the non-empty partitions are created, sorted by index
+        let partitions = split_hashmap(hashmap, 16, &TreeConfig::default());
+        assert_eq!(
+            partitions,
+            vec![
+                (0, vec![(3, FieldElement::from(3_u64))]),
+                (
+                    1,
+                    vec![
+                        (0, FieldElement::from(4_u64)),
+                        (1, FieldElement::from(2_u64))
+                    ]
+                ),
+                (1 << 36, vec![(0, FieldElement::from(1_u64))]),
+            ]
+        );
+
+        // ? Updating the partitions in parallel gives the same state as updating them one by one
+        let mut outputs = Vec::new();
+        let configs = [
+            TreeConfig::new().sequential(),
+            TreeConfig::new().with_sequential_threshold(0),
+        ];
+        for (run, tree_config) in configs.iter().enumerate() {
+            let storage = TestStorage::new(&format!("split_hashmap_{}", run));
+            let config = storage.config.clone();
+
+            let updated_state_hashes = (0..32_u64)
+                .map(|i| ((i * 37) % 1024, FieldElement::from(i + 1).to_string()))
+                .collect::<HashMap<u64, String>>();
+            let (_, new_root, preimage) = update_trees_with_config::<KeccakHasher>(
+                &config,
+                tree_config,
+                updated_state_hashes,
+                10,
+                4,
+            )
+            .unwrap();
+
+            outputs.push((new_root, preimage));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+
+        // ? Partition indices have to fit below u32::MAX, the index of the root tree
+        let storage = TestStorage::new("split_hashmap_depth");
+        let store: Arc<dyn NodeStore<Hash256>> = storage.file_store();
+
+        let update_leaf = |leaf_idx: u64, total_depth: u32, partition_size_exponent: u32| {
+            let mut updated_state_hashes = HashMap::new();
+            updated_state_hashes.insert(leaf_idx, "1".to_string());
+            update_trees_in_store::<KeccakHasher>(
+                &store,
+                updated_state_hashes,
+                total_depth,
+                partition_size_exponent,
+            )
+        };
+        for (total_depth, partition_size_exponent) in [(36, 4), (40, 4), (4, 8)] {
+            assert!(matches!(
+                update_leaf(0, total_depth, partition_size_exponent),
+                Err(MerkleError::InvalidDepth { .. })
+            ));
+        }
+        assert!(matches!(
+            state_root_at::<KeccakHasher>(&store, 0, 36, 4),
+            Err(MerkleError::InvalidDepth { .. })
+        ));
+        assert!(matches!(
+            update_leaf(1 << 35, 35, 4),
+            Err(MerkleError::IndexOutOfRange {
+                level: 4,
+                idx: 0x8000_0000,
+                depth: 35
+            })
+        ));
+
+        let last_leaf = (1 << 35) - 1;
+        let (_, new_root, _) = update_leaf(last_leaf, 35, 4).unwrap();
+        let proof = get_state_proof_at::<KeccakHasher>(&store, 1, last_leaf, 35, 4).unwrap();
+        assert!(proof.verify(&Hash256::from_str(&new_root).unwrap()));
+    }
+
+    #[test]
+    fn test_partitions_in_flight() {
+        let storage = TestStorage::new("partitions_in_flight");
+
+        let updated_state_hashes = (0..40_u64)
+            .map(|i| ((i * 53) % 1024, Hash256::from(i + 1).to_string()))
+            .collect::<HashMap<u64, String>>();
+
+        let mut outputs = Vec::new();
+        for (run, partitions_in_flight) in [256, 3, 1].into_iter().enumerate() {
+            let tree_config = TreeConfig::new()
+                .with_sequential_threshold(0)
+                .with_partitions_in_flight(partitions_in_flight);
+
+            let config = storage.namespace(format!("file_{}", run));
+            let file_output = update_trees_with_config::<KeccakHasher>(
+                &config,
+                &tree_config,
+                updated_state_hashes.clone(),
+                10,
+                4,
+            )
+            .unwrap();
+
+            let config = storage.namespace(format!("sled_{}", run));
+            let sled_store: Arc<dyn NodeStore<Hash256>> =
+                Arc::new(SledNodeStore::open(&config).unwrap());
+            let sled_output = update_trees_in_store_with_config::<KeccakHasher>(
+                &sled_store,
+                &tree_config,
+                updated_state_hashes.clone(),
+                10,
+                4,
+            )
+            .unwrap();
+            assert_eq!(file_output, sled_output);
+
+            // ? All the partitions are committed together with the root tree
+            let file_store: Arc<dyn NodeStore<Hash256>> = Arc::new(FileNodeStore::new(
+                storage.namespace(format!("file_{}", run)),
+            ));
+            for store in [&file_store, &sled_store] {
+                let root = state_root_at::<KeccakHasher>(store, 1, 10, 4).unwrap();
+                assert_eq!(root, file_output.1);
+
+                let proof = get_state_proof_at::<KeccakHasher>(store, 1, 53, 10, 4).unwrap();
+                assert_eq!(proof.leaf_hash, Hash256::from(2_u64));
+                assert!(proof.verify(&Hash256::from_str(&root).unwrap()));
+            }
+
+            outputs.push(file_output);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+
+        // ? A partition is only kept in memory until it is staged
+        #[derive(Debug)]
+        struct CacheProbe {
+            store: FileNodeStore,
+            max_cached_trees: AtomicUsize,
+        }
+
+        impl NodeStore<Hash256> for CacheProbe {
+            fn read_root(&self, tree_index: u32) -> Result<Option<Hash256>, MerkleError> {
+                self.store.read_root(tree_index)
+            }
+
+            fn read_node(
+                &self,
+                tree_index: u32,
+                level: u32,
+                idx: u64,
+            ) -> Result<Option<Hash256>, MerkleError> {
+                self.store.read_node(tree_index, level, idx)
+            }
+
+            fn read_level(
+                &self,
+                tree_index: u32,
+                level: u32,
+            ) -> Result<HashMap<u64, Hash256>, MerkleError> {
+                self.store.read_level(tree_index, level)
+            }
+
+            fn read_version(&self, tree_index: u32) -> Result<u64, MerkleError> {
+                NodeStore::<Hash256>::read_version(&self.store, tree_index)
+            }
+
+            fn read_history(
+                &self,
+                tree_index: u32,
+                from_version: u64,
+            ) -> Result<Vec<VersionRecord<Hash256>>, MerkleError> {
+                self.store.read_history(tree_index, from_version)
+            }
+
+            fn stage_trees(&self, updates: &[TreeUpdate<Hash256>]) -> Result<(), MerkleError> {
+                self.max_cached_trees
+                    .fetch_max(self.store.cached_trees(), Ordering::SeqCst);
+                self.store.stage_trees(updates)
+            }
+
+            fn commit_trees(&self, tree_indices: &[u32]) -> Result<(), MerkleError> {
+                NodeStore::<Hash256>::commit_trees(&self.store, tree_indices)
+            }
+        }
+
+        let probe = Arc::new(CacheProbe {
+            store: FileNodeStore::new(storage.namespace("probe")),
+            max_cached_trees: AtomicUsize::new(0),
+        });
+        let store: Arc<dyn NodeStore<Hash256>> = probe.clone();
+        let output = update_trees_in_store_with_config::<KeccakHasher>(
+            &store,
+            &TreeConfig::new()
+                .with_sequential_threshold(0)
+                .with_partitions_in_flight(3),
+            updated_state_hashes,
+            10,
+            4,
+        )
+        .unwrap();
+        assert_eq!(output, outputs[0]);
+        assert!((1..=3).contains(&probe.max_cached_trees.load(Ordering::SeqCst)));
+        assert_eq!(probe.store.cached_trees(), 0);
+    }
+
+    #[test]
+    fn test_preimage_transition() {
+        let mut tree = Tree::new(8, 0).unwrap();
+        let mut updated_hashes = HashMap::new();
+        for i in 0..10_u64 {
+            updated_hashes.insert(i * 11, FieldElement::from(i + 1));
+        }
+        tree.batch_transition_updates(&updated_hashes, &mut Preimage::new())
+            .unwrap();
+        let prev_root = tree.root;
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(11_u64, FieldElement::ZERO);
+        updated_hashes.insert(12_u64, FieldElement::from(5_u64));
+        updated_hashes.insert(250_u64, FieldElement::from(6_u64));
+        let mut preimage = Preimage::new();
+        tree.batch_transition_updates(&updated_hashes, &mut preimage)
+            .unwrap();
+
+        let check = |preimage: &Preimage, updated_hashes: &HashMap<u64, FieldElement>| {
+            preimage
+                .check_transition::<PedersenHasher>(&prev_root, &tree.root, updated_hashes, 8)
+                .unwrap()
+        };
+        assert!(check(&preimage, &updated_hashes).is_empty());
+
+        // ? A leaf the batch did not update, or a path without an entry, is reported
+        let mut wrong_hashes = updated_hashes.clone();
+        wrong_hashes.insert(12_u64, FieldElement::from(7_u64));
+        assert_eq!(
+            check(&preimage, &wrong_hashes),
+            vec![PreimageIssue::LeafMismatch {
+                idx: 12,
+                expected: FieldElement::from(7_u64),
+                found: FieldElement::from(5_u64),
+            }]
+        );
+
+        let mut incomplete = Preimage::new();
+        for (hash, children) in preimage.iter() {
+            if *hash != prev_root {
+                incomplete.insert(*hash, *children);
+            }
+        }
+        assert_eq!(
+            check(&incomplete, &updated_hashes),
+            vec![PreimageIssue::Missing {
+                side: TreeSide::Previous,
+                level: 8,
+                idx: 0,
+                hash: prev_root,
+            }]
+        );
+
+        // ? The preimage of the partitioned trees covers the whole state tree
+        let storage = TestStorage::new("preimage_transition");
+        let config = storage.config.clone();
+
+        let mut updated_hashes = HashMap::new();
+        updated_hashes.insert(5_u64, "7".to_string());
+        updated_hashes.insert(300_u64, "9".to_string());
+        let (prev_root, new_root, preimage) =
+            update_trees::<PedersenHasher>(&config, updated_hashes.clone(), 16, 8).unwrap();
+
+        let updated_hashes = updated_hashes
+            .iter()
+            .map(|(idx, hash)| (*idx, field_from_str(hash).unwrap()))
+            .collect::<HashMap<u64, FieldElement>>();
+        let issues = preimage
+            .check_transition::<PedersenHasher>(
+                &field_from_str(&prev_root).unwrap(),
+                &field_from_str(&new_root).unwrap(),
+                &updated_hashes,
+                16,
+            )
+            .unwrap();
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn test_update_witnesses() {
+        let mut tree = Tree::new(8, 0).unwrap();
+        let mut updated_hashes = HashMap::new();
+        for i in 0..10_u64 {
+            updated_hashes.insert(i * 11, FieldElement::from(i + 1));
+        }
+        tree.batch_transition_updates(&updated_hashes, &mut Preimage::new())
+            .unwrap();
+        let mut expected_tree = tree.clone();
+
+        let updates = vec![
+            (22_u64, FieldElement::from(5_u64)),
+            (23, FieldElement::from(6_u64)),
+            (200, FieldElement::ZERO),
+            (22, FieldElement::from(7_u64)),
+        ];
+        let mut prev_root = tree.root;
+        let witnesses = tree
+            .batch_transition_witnesses(&updates, &mut Preimage::new())
+            .unwrap();
+
+        // ? Every witness continues from the root of the one before
+        for ((idx, new_leaf), witness) in updates.iter().zip(witnesses.iter()) {
+            assert!(witness.verify(&prev_root));
+            assert_eq!(
+                witness.old_leaf,
+                expected_tree.get_proof(*idx).unwrap().leaf_hash
+            );
+
+            let mut updated_hashes = HashMap::new();
+            updated_hashes.insert(*idx, *new_leaf);
+            expected_tree
+                .batch_transition_updates(&updated_hashes, &mut Preimage::new())
+                .unwrap();
+            assert_eq!(witness.new_root, expected_tree.root);
+
+            prev_root = witness.new_root;
+        }
+        assert_eq!(tree.root, prev_root);
+        assert_eq!(tree.leaf_nodes, expected_tree.leaf_nodes);
+    }
 }
@@ -0,0 +1,128 @@
+use std::{error::Error, fmt};
+
+/// The maximum depth of a tree (leaf indexes are u64)
+pub const MAX_TREE_DEPTH: u32 = 64;
+
+#[derive(Debug)]
+pub enum MerkleError {
+    /// The node index does not fit in the given level of the tree (level 0 are the leaves)
+    IndexOutOfRange { level: u32, idx: u64, depth: u32 },
+    /// The level is not part of the tree (inner node levels are 1..=depth)
+    LevelOutOfRange { level: u32, depth: u32 },
+    /// The depth (+ shift) is greater than what is supported
+    DepthOverflow { depth: u32, max_depth: u32 },
+    /// The value is not a valid field element
+    InvalidFieldElement(String),
+    /// The value is not a valid 256 bit node
+    InvalidNode(String),
+    /// Reading or writing a tree from/to storage failed
+    Io(std::io::Error),
+    /// A stored tree could not be encoded or decoded
+    Serialization(String),
+    /// The node store failed to read or write nodes
+    Storage(String),
+    /// The tree has not been stored
+    TreeNotFound(u32),
+    /// A stored tree is truncated, fails its checksum or contains inconsistent nodes
+    Corrupted(String),
+    /// A stored tree was written in a format (version) that can not be read
+    UnsupportedFormat(String),
+    /// A stored tree was written with a different hasher, depth or shift than requested
+    TreeMismatch(String),
+    /// The state tree can't be split into partitions of that depth (see `update_trees`)
+    InvalidDepth {
+        total_depth: u32,
+        partition_size_exponent: u32,
+    },
+    /// The tree has no such version (versions are 0..=latest)
+    VersionOutOfRange { version: u64, latest: u64 },
+    /// The history of the version was dropped (see `TreeConfig::with_history_limit`), `oldest` is the oldest version left
+    VersionPruned { version: u64, oldest: u64 },
+    /// There is no batch left in memory that can be rolled back
+    NoBatchToRollback,
+    /// A tree can't be built without any leaves
+    NoLeaves,
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MerkleError::IndexOutOfRange { level, idx, depth } => write!(
+                f,
+                "index {} is out of range at level {} of a tree with depth {}",
+                idx, level, depth
+            ),
+            MerkleError::LevelOutOfRange { level, depth } => write!(
+                f,
+                "level {} is out of range for a tree with depth {}",
+                level, depth
+            ),
+            MerkleError::DepthOverflow { depth, max_depth } => write!(
+                f,
+                "depth {} is greater than the maximum depth {}",
+                depth, max_depth
+            ),
+            MerkleError::InvalidFieldElement(value) => {
+                write!(f, "{:?} is not a valid field element", value)
+            }
+            MerkleError::InvalidNode(value) => write!(f, "{:?} is not a valid 256 bit node", value),
+            MerkleError::Io(err) => write!(f, "storage io error: {}", err),
+            MerkleError::Serialization(err) => write!(f, "serialization error: {}", err),
+            MerkleError::Storage(err) => write!(f, "node store error: {}", err),
+            MerkleError::TreeNotFound(tree_index) => write!(f, "tree {} is not stored", tree_index),
+            MerkleError::Corrupted(err) => write!(f, "corrupted tree: {}", err),
+            MerkleError::UnsupportedFormat(err) => write!(f, "unsupported format: {}", err),
+            MerkleError::TreeMismatch(err) => write!(f, "tree mismatch: {}", err),
+            MerkleError::InvalidDepth {
+                total_depth,
+                partition_size_exponent,
+            } => write!(
+                f,
+                "a tree of depth {} can't be split into partitions of depth {}",
+                total_depth, partition_size_exponent
+            ),
+            MerkleError::VersionOutOfRange { version, latest } => write!(
+                f,
+                "version {} does not exist, the latest version is {}",
+                version, latest
+            ),
+            MerkleError::VersionPruned { version, oldest } => write!(
+                f,
+                "the history of version {} was dropped, the oldest version is {}",
+                version, oldest
+            ),
+            MerkleError::NoBatchToRollback => write!(f, "there is no batch to roll back"),
+            MerkleError::NoLeaves => write!(f, "a tree can't be built without leaves"),
+        }
+    }
+}
+
+impl Error for MerkleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MerkleError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MerkleError {
+    fn from(err: std::io::Error) -> Self {
+        MerkleError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for MerkleError {
+    fn from(err: bincode::Error) -> Self {
+        MerkleError::Serialization(err.to_string())
+    }
+}
+
+impl From<sled::Error> for MerkleError {
+    fn from(err: sled::Error) -> Self {
+        match err {
+            sled::Error::Io(err) => MerkleError::Io(err),
+            err => MerkleError::Storage(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    fmt::{self, Debug, Display},
+    hash::Hash,
+    str::FromStr,
+};
+
+use num_bigint::BigUint;
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use starknet_crypto::FieldElement;
+
+use super::errors::MerkleError;
+
+/// The hash function used to combine two child nodes into their parent.
+pub trait MerkleHasher:
+    Debug + Clone + Copy + Default + PartialEq + Eq + Send + Sync + 'static
+{
+    /// The nodes of the tree, Starknet field elements for the Starknet hash functions
+    /// and the full 32 byte digest otherwise.
+    type Node: MerkleNode;
+
+    /// Identifies the hash function in stored trees, has to be unique for every hasher.
+    const ID: u8;
+
+    /// Hashes the left and right child into the parent node.
+    fn hash(left: &Self::Node, right: &Self::Node) -> Self::Node;
+}
+
+/// A node of a tree, stored as 32 big-endian bytes and written as a string at the API edges
+/// (the json preimage and the string updates of `update_trees`).
+pub trait MerkleNode:
+    Copy + Eq + Ord + Hash + Debug + Display + From<u64> + Send + Sync + 'static
+{
+    /// The empty leaf
+    const ZERO: Self;
+
+    fn to_bytes_be(&self) -> [u8; 32];
+
+    fn from_bytes_be(bytes: &[u8; 32]) -> Result<Self, MerkleError>;
+
+    /// Parses a decimal (or 0x prefixed hex) string into a node.
+    fn from_str(value: &str) -> Result<Self, MerkleError>;
+}
+
+impl MerkleNode for FieldElement {
+    const ZERO: Self = FieldElement::ZERO;
+
+    fn to_bytes_be(&self) -> [u8; 32] {
+        FieldElement::to_bytes_be(self)
+    }
+
+    fn from_bytes_be(bytes: &[u8; 32]) -> Result<Self, MerkleError> {
+        FieldElement::from_bytes_be(bytes)
+            .map_err(|_| MerkleError::InvalidFieldElement(format!("0x{}", to_hex(bytes))))
+    }
+
+    fn from_str(value: &str) -> Result<Self, MerkleError> {
+        <FieldElement as FromStr>::from_str(value)
+            .map_err(|_| MerkleError::InvalidFieldElement(value.to_string()))
+    }
+}
+
+/// A full 256 bit digest (the nodes of the `KeccakHasher` and `Sha256Hasher` trees),
+/// written as a 0x prefixed hex string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash256(pub [u8; 32]);
+
+impl MerkleNode for Hash256 {
+    const ZERO: Self = Hash256([0; 32]);
+
+    fn to_bytes_be(&self) -> [u8; 32] {
+        self.0
+    }
+
+    fn from_bytes_be(bytes: &[u8; 32]) -> Result<Self, MerkleError> {
+        Ok(Hash256(*bytes))
+    }
+
+    fn from_str(value: &str) -> Result<Self, MerkleError> {
+        let number = match value.strip_prefix("0x") {
+            Some(hex) => BigUint::parse_bytes(hex.as_bytes(), 16),
+            None => BigUint::parse_bytes(value.as_bytes(), 10),
+        };
+
+        let bytes = match number {
+            Some(number) => number.to_bytes_be(),
+            None => return Err(MerkleError::InvalidNode(value.to_string())),
+        };
+        if bytes.len() > 32 {
+            return Err(MerkleError::InvalidNode(value.to_string()));
+        }
+
+        let mut node = [0_u8; 32];
+        node[32 - bytes.len()..].copy_from_slice(&bytes);
+
+        Ok(Hash256(node))
+    }
+}
+
+impl From<u64> for Hash256 {
+    fn from(value: u64) -> Self {
+        let mut node = [0_u8; 32];
+        node[24..].copy_from_slice(&value.to_be_bytes());
+
+        Hash256(node)
+    }
+}
+
+impl Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", to_hex(&self.0))
+    }
+}
+
+fn to_hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PedersenHasher;
+
+impl MerkleHasher for PedersenHasher {
+    type Node = FieldElement;
+
+    const ID: u8 = 0;
+
+    fn hash(left: &FieldElement, right: &FieldElement) -> FieldElement {
+        starknet_crypto::pedersen_hash(left, right)
+    }
+}
+
+/// The Starknet variant of poseidon (Hades permutation with a width of 3)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoseidonHasher;
+
+impl MerkleHasher for PoseidonHasher {
+    type Node = FieldElement;
+
+    const ID: u8 = 1;
+
+    fn hash(left: &FieldElement, right: &FieldElement) -> FieldElement {
+        starknet_crypto::poseidon_hash(*left, *right)
+    }
+}
+
+/// Keccak256 of the two 32 byte big-endian children, the same tree as one built on L1 with
+/// `keccak256(abi.encodePacked(left, right))`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeccakHasher;
+
+impl MerkleHasher for KeccakHasher {
+    type Node = Hash256;
+
+    const ID: u8 = 2;
+
+    fn hash(left: &Hash256, right: &Hash256) -> Hash256 {
+        digest_hash::<Keccak256>(left, right)
+    }
+}
+
+/// Sha256 of the two 32 byte big-endian children (`sha256(abi.encodePacked(left, right))`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha256Hasher;
+
+impl MerkleHasher for Sha256Hasher {
+    type Node = Hash256;
+
+    const ID: u8 = 3;
+
+    fn hash(left: &Hash256, right: &Hash256) -> Hash256 {
+        digest_hash::<Sha256>(left, right)
+    }
+}
+
+fn digest_hash<D: Digest>(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = D::new();
+    hasher.update(left.0);
+    hasher.update(right.0);
+
+    let mut bytes = [0_u8; 32];
+    bytes.copy_from_slice(&hasher.finalize()[..32]);
+
+    return Hash256(bytes);
+}
@@ -0,0 +1,41 @@
+use starknet_crypto::FieldElement;
+
+use super::hashers::MerkleNode;
+
+/// The nodes a batch overwrote, which is all that is needed to look at the tree as it was
+/// before the batch (every other node is shared with the newer versions).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionRecord<N: MerkleNode = FieldElement> {
+    /// The version the batch produced
+    pub version: u64,
+    /// The root before the batch
+    pub prev_root: N,
+    /// (level, idx, node before the batch) sorted by level and index, `None` if the node was the zero hash
+    pub prev_nodes: Vec<(u32, u64, Option<N>)>,
+}
+
+impl<N: MerkleNode> VersionRecord<N> {
+    /// The node before the batch, `None` if the batch did not change it.
+    pub fn prev_node(&self, level: u32, idx: u64) -> Option<Option<N>> {
+        let pos = self
+            .prev_nodes
+            .binary_search_by_key(&(level, idx), |(level, idx, _)| (*level, *idx))
+            .ok()?;
+
+        return Some(self.prev_nodes[pos].2);
+    }
+}
+
+/// The node at (level, idx) in the oldest of the versions, `None` if none of them changed it
+/// (it is the same as in the latest version).
+///
+/// `records` are the records of all the versions after the requested one, sorted by version.
+pub fn node_before<'a, N: MerkleNode>(
+    records: impl IntoIterator<Item = &'a VersionRecord<N>>,
+    level: u32,
+    idx: u64,
+) -> Option<Option<N>> {
+    records
+        .into_iter()
+        .find_map(|record| record.prev_node(level, idx))
+}
@@ -1,17 +1,22 @@
-use std::str::FromStr;
-
-use starknet_crypto::FieldElement;
-
+pub mod errors;
+pub mod hashers;
+pub mod history;
+pub mod node_store;
 pub mod parallelization;
+pub mod preimage;
+pub mod proofs;
 pub mod state_tansitions;
 pub mod storage;
 pub mod tree_utils;
 
-pub fn pedersen(a: &String, b: &String) -> String {
-    let a = FieldElement::from_str(&a).unwrap();
-    let b = FieldElement::from_str(&b).unwrap();
+use errors::MerkleError;
+use tree_utils::field_from_str;
+
+pub fn pedersen(a: &String, b: &String) -> Result<String, MerkleError> {
+    let a = field_from_str(a)?;
+    let b = field_from_str(b)?;
 
     let hash = starknet_crypto::pedersen_hash(&a, &b);
 
-    return hash.to_string();
+    return Ok(hash.to_string());
 }
@@ -0,0 +1,415 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use parking_lot::{Mutex, RwLock};
+use starknet_crypto::FieldElement;
+
+use crate::utils::{
+    errors::MerkleError,
+    hashers::{Hash256, MerkleNode},
+    history::VersionRecord,
+    storage::{
+        _stage_delta_to_disk_inner, _stage_history_inner, commit_staged_deltas,
+        compact_large_deltas, decode_node, discard_staged_history, read_history, read_tree_file,
+        read_version, DecodedTree, StorageConfig, TreeHeader, FORMAT_VERSION,
+    },
+};
+
+/// The nodes of a tree that changed in a batch, written to a `NodeStore` in one go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeUpdate<N: MerkleNode = FieldElement> {
+    pub tree_index: u32,
+    pub depth: u32,
+    pub shift: u32,
+    /// `MerkleHasher::ID` of the hasher the tree is built with
+    pub hasher_id: u8,
+    pub root: N,
+    /// (level, idx, node) where level 0 are the leaves and `None` removes the node (it is a zero hash)
+    pub nodes: Vec<(u32, u64, Option<N>)>,
+    /// The version of the tree after the update
+    pub version: u64,
+    /// The records of the versions produced since the tree was last written, sorted by version
+    pub history: Vec<VersionRecord<N>>,
+}
+
+/// A storage backend that trees read and write their nodes through.
+///
+/// Nodes are addressed by (tree index, level, index) where level 0 are the leaves.
+/// Only the non-zero nodes are stored, a missing node is the zero hash of its level.
+pub trait NodeStore<N: MerkleNode = FieldElement>: Debug + Send + Sync {
+    /// The stored root of the tree, `None` if the tree has not been stored yet.
+    fn read_root(&self, tree_index: u32) -> Result<Option<N>, MerkleError>;
+
+    /// A single stored node, `None` if it is not stored.
+    fn read_node(&self, tree_index: u32, level: u32, idx: u64) -> Result<Option<N>, MerkleError>;
+
+    /// All the stored nodes of a level.
+    fn read_level(&self, tree_index: u32, level: u32) -> Result<HashMap<u64, N>, MerkleError>;
+
+    /// The latest version of the tree, 0 if it has no history.
+    fn read_version(&self, tree_index: u32) -> Result<u64, MerkleError>;
+
+    /// The history records of the tree with a version greater than `from_version`, sorted by version.
+    fn read_history(
+        &self,
+        tree_index: u32,
+        from_version: u64,
+    ) -> Result<Vec<VersionRecord<N>>, MerkleError>;
+
+    /// Writes the updates of several trees atomically (together with their history records),
+    /// either all of them are visible afterwards or none.
+    fn write_trees(&self, updates: &[TreeUpdate<N>]) -> Result<(), MerkleError> {
+        self.stage_trees(updates)?;
+
+        let tree_indices: Vec<u32> = updates.iter().map(|update| update.tree_index).collect();
+        self.commit_trees(&tree_indices)
+    }
+
+    /// Writes the updates of some trees without making them visible until `commit_trees` is called
+    /// for them, it can be called concurrently for different trees.
+    /// Staging a tree again replaces its staged updates.
+    fn stage_trees(&self, updates: &[TreeUpdate<N>]) -> Result<(), MerkleError>;
+
+    /// Makes the staged updates of the trees visible atomically.
+    fn commit_trees(&self, tree_indices: &[u32]) -> Result<(), MerkleError>;
+}
+
+// * FILESYSTEM * //
+
+/// Stores every tree as a single file in the folder of the `StorageConfig` (the `Tree::store_to_disk` format)
+/// and appends the nodes written to it as deltas next to the tree file.
+/// The history records of a tree are appended to a history file next to it.
+///
+/// A tree file is decoded the first time one of its nodes is read and kept in memory until the tree
+/// is staged (the staged updates only live on disk), so a new store should be created if the files
+/// are changed outside of it. The decoded trees keep the raw node bytes, so the same store can be
+/// used by trees with different hashers.
+#[derive(Debug)]
+pub struct FileNodeStore {
+    pub config: StorageConfig,
+    trees: RwLock<HashMap<u32, Option<Arc<DecodedTree<Hash256>>>>>,
+}
+
+impl FileNodeStore {
+    pub fn new(config: StorageConfig) -> FileNodeStore {
+        FileNodeStore {
+            config,
+            trees: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The number of decoded trees kept in memory
+    pub fn cached_trees(&self) -> usize {
+        self.trees.read().len()
+    }
+
+    fn tree(&self, tree_index: u32) -> Result<Option<Arc<DecodedTree<Hash256>>>, MerkleError> {
+        if let Some(tree) = self.trees.read().get(&tree_index) {
+            return Ok(tree.clone());
+        }
+
+        let tree = read_tree_file(&self.config, tree_index)?.map(Arc::new);
+        self.trees.write().insert(tree_index, tree.clone());
+
+        Ok(tree)
+    }
+}
+
+impl<N: MerkleNode> NodeStore<N> for FileNodeStore {
+    fn read_root(&self, tree_index: u32) -> Result<Option<N>, MerkleError> {
+        self.tree(tree_index)?
+            .map(|tree| decode_node(&tree.2 .0))
+            .transpose()
+    }
+
+    fn read_node(&self, tree_index: u32, level: u32, idx: u64) -> Result<Option<N>, MerkleError> {
+        let tree = match self.tree(tree_index)? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+
+        let node = if level == 0 {
+            tree.0.get(&idx)
+        } else {
+            tree.1
+                .get(level as usize - 1)
+                .and_then(|nodes| nodes.get(&idx))
+        };
+
+        node.map(|node| decode_node(&node.0)).transpose()
+    }
+
+    fn read_level(&self, tree_index: u32, level: u32) -> Result<HashMap<u64, N>, MerkleError> {
+        let tree = match self.tree(tree_index)? {
+            Some(tree) => tree,
+            None => return Ok(HashMap::new()),
+        };
+
+        let nodes = if level == 0 {
+            Some(&tree.0)
+        } else {
+            tree.1.get(level as usize - 1)
+        };
+
+        nodes
+            .into_iter()
+            .flatten()
+            .map(|(idx, node)| Ok((*idx, decode_node(&node.0)?)))
+            .collect()
+    }
+
+    fn read_version(&self, tree_index: u32) -> Result<u64, MerkleError> {
+        read_version(&self.config, tree_index)
+    }
+
+    fn read_history(
+        &self,
+        tree_index: u32,
+        from_version: u64,
+    ) -> Result<Vec<VersionRecord<N>>, MerkleError> {
+        read_history(&self.config, tree_index, from_version)
+    }
+
+    fn stage_trees(&self, updates: &[TreeUpdate<N>]) -> Result<(), MerkleError> {
+        for update in updates {
+            let header = TreeHeader {
+                version: FORMAT_VERSION,
+                hasher_id: update.hasher_id,
+                depth: update.depth,
+                shift: update.shift,
+            };
+            _stage_delta_to_disk_inner(
+                &self.config,
+                &header,
+                &update.nodes,
+                &update.root,
+                update.tree_index,
+            )?;
+            if update.history.is_empty() {
+                // ? Staging the tree again replaces the history records staged before
+                discard_staged_history(&self.config, update.tree_index)?;
+            } else {
+                _stage_history_inner(&self.config, &header, &update.history, update.tree_index)?;
+            }
+
+            // ? The staged updates only live on disk, the tree is decoded again if it is read afterwards
+            self.trees.write().remove(&update.tree_index);
+        }
+
+        Ok(())
+    }
+
+    fn commit_trees(&self, tree_indices: &[u32]) -> Result<(), MerkleError> {
+        // ? The staged deltas of all the trees are committed together
+        commit_staged_deltas(&self.config, tree_indices)?;
+        compact_large_deltas(&self.config, tree_indices)?;
+
+        // ? The committed trees are read from disk (with their new deltas) the next time they are needed
+        let mut trees = self.trees.write();
+        for tree_index in tree_indices {
+            trees.remove(tree_index);
+        }
+
+        Ok(())
+    }
+}
+
+// * SLED * //
+
+/// The level under which the root of a tree is stored
+const ROOT_LEVEL: u32 = u32::MAX;
+/// The level under which the history records of a tree are stored (indexed by version)
+const HISTORY_LEVEL: u32 = u32::MAX - 1;
+
+/// The staged (key, value) writes of every tree, `None` removes the key
+type StagedWrites = HashMap<u32, Vec<([u8; 16], Option<Vec<u8>>)>>;
+
+/// Stores every node under its own (tree index, level, idx) key in a sled tree,
+/// so a tree can be loaded lazily one node at a time.
+///
+/// Sled applies a batch from memory, so unlike the `FileNodeStore` the staged writes of the trees
+/// are kept in memory until they are committed.
+#[derive(Debug, Clone)]
+pub struct SledNodeStore {
+    pub db: sled::Tree,
+    staged: Arc<Mutex<StagedWrites>>, // written in one sled batch by `commit_trees`
+}
+
+impl SledNodeStore {
+    /// Opens (or creates) the sled database at `<base_path>/<namespace>.sled`.
+    pub fn open(config: &StorageConfig) -> Result<SledNodeStore, MerkleError> {
+        let db = sled::open(config.tree_dir().with_extension("sled"))?;
+
+        Ok(SledNodeStore::new(
+            db.open_tree(config.namespace.as_bytes())?,
+        ))
+    }
+
+    /// Uses an already opened sled tree (e.g. to keep several namespaces in one database).
+    pub fn new(db: sled::Tree) -> SledNodeStore {
+        SledNodeStore {
+            db,
+            staged: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+/// The big-endian (tree_index, level, idx) key, so that the nodes of a level are stored next to each other
+fn node_key(tree_index: u32, level: u32, idx: u64) -> [u8; 16] {
+    let mut key = [0_u8; 16];
+    key[..4].copy_from_slice(&tree_index.to_be_bytes());
+    key[4..8].copy_from_slice(&level.to_be_bytes());
+    key[8..].copy_from_slice(&idx.to_be_bytes());
+
+    return key;
+}
+
+fn decode_value<N: MerkleNode>(value: &[u8]) -> Result<N, MerkleError> {
+    let bytes: [u8; 32] = value
+        .try_into()
+        .map_err(|_| MerkleError::Serialization("invalid node length in store".to_string()))?;
+
+    decode_node(&bytes)
+}
+
+/// (previous root, previous nodes) of a history record, the version is part of the key
+type EncodedVersionRecord = ([u8; 32], Vec<(u32, u64, Option<[u8; 32]>)>);
+
+fn encode_record<N: MerkleNode>(record: &VersionRecord<N>) -> Result<Vec<u8>, MerkleError> {
+    let nodes = record
+        .prev_nodes
+        .iter()
+        .map(|(level, idx, node)| (*level, *idx, node.map(|node| node.to_bytes_be())))
+        .collect::<Vec<(u32, u64, Option<[u8; 32]>)>>();
+
+    Ok(bincode::serialize(&(
+        record.prev_root.to_bytes_be(),
+        nodes,
+    ))?)
+}
+
+fn decode_record<N: MerkleNode>(
+    version: u64,
+    value: &[u8],
+) -> Result<VersionRecord<N>, MerkleError> {
+    let (prev_root, prev_nodes): EncodedVersionRecord = bincode::deserialize(value)?;
+
+    let prev_nodes = prev_nodes
+        .into_iter()
+        .map(|(level, idx, node)| Ok((level, idx, node.map(|n| decode_node(&n)).transpose()?)))
+        .collect::<Result<Vec<(u32, u64, Option<N>)>, MerkleError>>()?;
+
+    Ok(VersionRecord {
+        version,
+        prev_root: decode_node(&prev_root)?,
+        prev_nodes,
+    })
+}
+
+/// The idx (version) part of a node key
+fn key_idx(key: &[u8]) -> u64 {
+    let mut idx = [0_u8; 8];
+    idx.copy_from_slice(&key[8..16]);
+
+    return u64::from_be_bytes(idx);
+}
+
+impl<N: MerkleNode> NodeStore<N> for SledNodeStore {
+    fn read_root(&self, tree_index: u32) -> Result<Option<N>, MerkleError> {
+        self.read_node(tree_index, ROOT_LEVEL, 0)
+    }
+
+    fn read_node(&self, tree_index: u32, level: u32, idx: u64) -> Result<Option<N>, MerkleError> {
+        match self.db.get(node_key(tree_index, level, idx))? {
+            Some(value) => Ok(Some(decode_value(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_level(&self, tree_index: u32, level: u32) -> Result<HashMap<u64, N>, MerkleError> {
+        let prefix = &node_key(tree_index, level, 0)[..8];
+
+        let mut nodes: HashMap<u64, N> = HashMap::new();
+        for entry in self.db.scan_prefix(prefix) {
+            let (key, value) = entry?;
+
+            nodes.insert(key_idx(&key), decode_value(&value)?);
+        }
+
+        Ok(nodes)
+    }
+
+    fn read_version(&self, tree_index: u32) -> Result<u64, MerkleError> {
+        let prefix = &node_key(tree_index, HISTORY_LEVEL, 0)[..8];
+
+        match self.db.scan_prefix(prefix).next_back() {
+            Some(entry) => Ok(key_idx(&entry?.0)),
+            None => Ok(0),
+        }
+    }
+
+    fn read_history(
+        &self,
+        tree_index: u32,
+        from_version: u64,
+    ) -> Result<Vec<VersionRecord<N>>, MerkleError> {
+        if from_version == u64::MAX {
+            return Ok(Vec::new());
+        }
+
+        let start = node_key(tree_index, HISTORY_LEVEL, from_version + 1);
+        let end = node_key(tree_index, HISTORY_LEVEL, u64::MAX);
+
+        let mut records: Vec<VersionRecord<N>> = Vec::new();
+        for entry in self.db.range(start..=end) {
+            let (key, value) = entry?;
+
+            records.push(decode_record(key_idx(&key), &value)?);
+        }
+
+        Ok(records)
+    }
+
+    fn stage_trees(&self, updates: &[TreeUpdate<N>]) -> Result<(), MerkleError> {
+        for update in updates {
+            let mut writes: Vec<([u8; 16], Option<Vec<u8>>)> = Vec::new();
+
+            for (level, idx, node) in update.nodes.iter() {
+                let key = node_key(update.tree_index, *level, *idx);
+                writes.push((key, node.map(|node| node.to_bytes_be().to_vec())));
+            }
+
+            let root_key = node_key(update.tree_index, ROOT_LEVEL, 0);
+            writes.push((root_key, Some(update.root.to_bytes_be().to_vec())));
+
+            for record in update.history.iter() {
+                let key = node_key(update.tree_index, HISTORY_LEVEL, record.version);
+                writes.push((key, Some(encode_record(record)?)));
+            }
+
+            self.staged.lock().insert(update.tree_index, writes);
+        }
+
+        Ok(())
+    }
+
+    fn commit_trees(&self, tree_indices: &[u32]) -> Result<(), MerkleError> {
+        // ? A sled batch is applied atomically
+        let mut batch = sled::Batch::default();
+
+        let mut staged = self.staged.lock();
+        for tree_index in tree_indices {
+            for (key, value) in staged.remove(tree_index).unwrap_or_default() {
+                match value {
+                    Some(value) => batch.insert(&key, value),
+                    None => batch.remove(&key),
+                }
+            }
+        }
+        drop(staged);
+
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+}
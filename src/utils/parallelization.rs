@@ -1,482 +1,262 @@
-use std::{collections::HashMap, sync::Arc, time::Instant};
+use std::{sync::Arc, time::Instant};
 
-use parking_lot::Mutex;
-use serde_json::{Map, Value};
+use rayon::{
+    prelude::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator, ParallelSlice},
+    ThreadPool,
+};
 
 use crate::{
-    utils::{pedersen, tree_utils::get_zero_hash},
+    utils::{errors::MerkleError, hashers::MerkleHasher, tree_utils::get_zero_hash},
     Tree,
 };
 
 // * =================================================================================================================
 // * HELPER FUNCTION FOR PARALLEL UPDATES
 
-const STRIDE: usize = 250; // Must be even
-
-pub fn split_and_run_first_row(
-    tree_mutex: &Arc<Mutex<&mut Tree>>,
-    preimage_mutex: &Arc<Mutex<&mut Map<String, Value>>>,
-    updated_hashes: &HashMap<u64, String>,
-    n: usize,
-) -> HashMap<u64, String> {
-    let next_row_proofs: HashMap<u64, String> = HashMap::new();
-    let next_row_proofs_mutex = Arc::new(Mutex::new(next_row_proofs));
-
-    split_and_run_first_row_inner(
-        tree_mutex,
-        preimage_mutex,
-        updated_hashes,
-        &next_row_proofs_mutex,
-        n,
-    );
-
-    let res = next_row_proofs_mutex.lock();
-    return res.to_owned();
+const DEFAULT_CHUNK_SIZE: usize = 250;
+const DEFAULT_SEQUENTIAL_THRESHOLD: usize = 64;
+const DEFAULT_PARTITIONS_IN_FLIGHT: usize = 256;
+const DEFAULT_HISTORY_LIMIT: usize = 0;
+
+/// How the tree updates are spread over threads.
+///
+/// By default the work runs on the global rayon pool, a dedicated `ThreadPool` keeps big batches
+/// from starving other users of the global pool.
+#[derive(Debug, Clone)]
+pub struct TreeConfig {
+    /// The minimum number of nodes hashed by one task (always even)
+    pub chunk_size: usize,
+    /// The pool the work runs on, the global rayon pool if `None`
+    pub thread_pool: Option<Arc<ThreadPool>>,
+    /// Levels (and batches) with fewer nodes than this are processed on the calling thread
+    pub sequential_threshold: usize,
+    /// The maximum number of partition trees `update_trees` keeps in memory at the same time
+    pub partitions_in_flight: usize,
+    /// The maximum number of versions a tree keeps in memory until it is written to storage,
+    /// 0 (the default) keeps no history
+    pub history_limit: usize,
 }
 
-fn split_and_run_first_row_inner(
-    tree_mutex: &Arc<Mutex<&mut Tree>>,
-    preimage_mutex: &Arc<Mutex<&mut Map<String, Value>>>,
-    updated_hashes: &HashMap<u64, String>,
-    next_row: &Arc<Mutex<HashMap<u64, String>>>,
-    n: usize,
-) {
-    // ? n counts how deep in the recursion loop we are
-    // ? at each iteration we take four elements from the hashmap and update the tree
-
-    let elems: Vec<(&u64, &String)> = updated_hashes
-        .iter()
-        .skip(n * STRIDE)
-        .take(STRIDE)
-        .collect();
-
-    // ? As long as there are elements in the map (elems.len() > 0) we keep splitting
-    // ? Pass the rest forward recursively to run in parallel
-    if elems.len() > 0 {
-        rayon::join(
-            || {
-                let next_row_indexes =
-                    build_first_row(tree_mutex, preimage_mutex, elems, updated_hashes);
-                let mut next_proofs = next_row.lock();
-                for (i, prev_res) in next_row_indexes {
-                    next_proofs.insert(i, prev_res);
-                }
-                drop(next_proofs);
-            },
-            || {
-                split_and_run_first_row_inner(
-                    tree_mutex,
-                    preimage_mutex,
-                    updated_hashes,
-                    next_row,
-                    n + 1,
-                )
-            },
-        );
+impl TreeConfig {
+    pub fn new() -> TreeConfig {
+        TreeConfig::default()
     }
-}
 
-// ------------------------------
-
-pub fn split_and_run_next_row(
-    tree_mutex: &Arc<Mutex<&mut Tree>>,
-    preimage_mutex: &Arc<Mutex<&mut Map<String, Value>>>,
-    updated_hashes: &HashMap<u64, String>,
-    row_depth: usize,
-    n: usize,
-) -> HashMap<u64, String> {
-    let next_row_proofs: HashMap<u64, String> = HashMap::new();
-    let next_row_proofs_mutex = Arc::new(Mutex::new(next_row_proofs));
-
-    split_and_run_next_row_inner(
-        tree_mutex,
-        preimage_mutex,
-        updated_hashes,
-        &next_row_proofs_mutex,
-        row_depth,
-        n,
-    );
-
-    let res = next_row_proofs_mutex.lock();
-    return res.to_owned();
-}
+    /// Sets the minimum number of nodes hashed by one task (rounded up to an even number, at least 2).
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> TreeConfig {
+        self.chunk_size = (chunk_size.max(2) + 1) & !1;
+        self
+    }
 
-fn split_and_run_next_row_inner(
-    tree_mutex: &Arc<Mutex<&mut Tree>>,
-    preimage_mutex: &Arc<Mutex<&mut Map<String, Value>>>,
-    updated_hashes: &HashMap<u64, String>,
-    next_row: &Arc<Mutex<HashMap<u64, String>>>,
-    row_depth: usize,
-    n: usize,
-) {
-    // ? n counts how deep in the recursion loop we are
-    // ? at each iteration we take four elements from the hashmap and update the tree
-
-    let elems: Vec<(&u64, &String)> = updated_hashes
-        .iter()
-        .skip(n * STRIDE)
-        .take(STRIDE)
-        .collect();
-
-    // ? As long as there are elements in the map (elems.len() > 0) we keep splitting
-    // ? Pass the rest forward recursively to run in parallel
-    if elems.len() > 0 {
-        rayon::join(
-            || {
-                let next_row_indexes =
-                    build_next_row(tree_mutex, preimage_mutex, elems, updated_hashes, row_depth);
-                let mut next_proofs = next_row.lock();
-                for (i, prev_res) in next_row_indexes {
-                    next_proofs.insert(i, prev_res);
-                }
-                drop(next_proofs);
-            },
-            || {
-                split_and_run_next_row_inner(
-                    tree_mutex,
-                    preimage_mutex,
-                    updated_hashes,
-                    next_row,
-                    row_depth,
-                    n + 1,
-                )
-            },
-        );
+    /// Runs the work on `thread_pool` instead of the global rayon pool.
+    pub fn with_thread_pool(mut self, thread_pool: Arc<ThreadPool>) -> TreeConfig {
+        self.thread_pool = Some(thread_pool);
+        self
     }
-}
 
-// ------------------------------
-
-fn build_first_row(
-    tree_mutex: &Arc<Mutex<&mut Tree>>,
-    preimage_mutex: &Arc<Mutex<&mut Map<String, Value>>>,
-    entries: Vec<(&u64, &String)>, // 4 entries taken from the hashmap to be updated in parallel
-    hashes: &HashMap<u64, String>, // the whole hashmap
-) -> Vec<(u64, String)> {
-    // next row stores the indexes of the next row that need to be updated
-    // (and the previous result hashes for the init state preimage)
-    let mut next_row: Vec<(u64, String)> = Vec::new();
-
-    for (idx, hash) in entries.iter() {
-        // ! Left child
-        if *idx % 2 == 0 {
-            //? If the right child exists, hash them together in the next loop
-            if hashes.get(&(*idx + 1)).is_some() {
-                continue;
-            }
-            //? If the right child doesn't exist (wasn't updated), hash the left child with the previous value in the state tree
-            else {
-                // ? Get the previous values in the state tree
-                let tree = tree_mutex.lock();
-                let init_left_hash = tree.nth_leaf_node(**idx);
-                let right_hash = &tree.nth_leaf_node(*idx + 1);
-                drop(tree);
-
-                // ? Hash the left child with the right child
-                let new_hash = pedersen(&hash, &right_hash);
-
-                // ? Use the new_hash to update the merkle tree
-                let mut tree = tree_mutex.lock();
-                let prev_res_hash = tree.ith_inner_node(1, *idx / 2);
-                tree.update_inner_node(1, *idx / 2, new_hash.clone());
-                drop(tree);
-
-                next_row.push((*idx / 2, prev_res_hash.clone()));
-
-                // * Preimages -----------------------------------------------------------------------------------------------
-
-                // ? Insert the new hash info into the preimage
-                let mut preimage = preimage_mutex.lock();
-
-                if !preimage.contains_key(&prev_res_hash.to_string()) {
-                    preimage.insert(
-                        prev_res_hash.to_string(),
-                        serde_json::to_value([init_left_hash.to_string(), right_hash.to_string()])
-                            .unwrap(),
-                    );
-                }
-
-                preimage.insert(
-                    new_hash.to_string(),
-                    serde_json::to_value([hash.to_string(), right_hash.to_string()]).unwrap(),
-                );
-                drop(preimage);
-
-                // * Preimages -----------------------------------------------------------------------------------------------
-
-                // ? update the leaf node with the hash
-                let mut tree = tree_mutex.lock();
-                tree.update_leaf_node(hash, **idx);
-                drop(tree);
-            }
-        }
-        // ! Right child
-        else {
-            // ? get the left child hash
-            let left_hash: String;
-            let prev_left_hash: String;
-            let prev_right_hash: String;
-            if hashes.get(&(*idx - 1)).is_some() {
-                // ? If the left child exists, hash them together
-                left_hash = hashes.get(&(*idx - 1)).unwrap().clone();
-                let mut tree = tree_mutex.lock();
-                prev_left_hash = tree.nth_leaf_node(*idx - 1);
-                prev_right_hash = tree.nth_leaf_node(**idx);
-
-                // ? Update the nodes in the tree with the hashes
-                tree.update_leaf_node(&left_hash, **idx - 1);
-                tree.update_leaf_node(hash, **idx);
-
-                drop(tree);
-            } else {
-                //? If the left child doesn't exist, hash the right child with the previous value in the state tree
-                let mut tree = tree_mutex.lock();
-                left_hash = tree.nth_leaf_node(*idx - 1);
-                prev_left_hash = tree.nth_leaf_node(*idx - 1);
-                prev_right_hash = tree.nth_leaf_node(**idx);
-
-                // ? Update the nodes in the tree with the hashes
-                tree.update_leaf_node(hash, **idx);
-                drop(tree);
-            };
-
-            // ? Hash the left child with the right child
-            let new_hash = pedersen(&left_hash, &hash);
-
-            // ? Use the new_hash to update the merkle tree
-            let mut tree = tree_mutex.lock();
-            let prev_res_hash = tree.ith_inner_node(1, *idx / 2);
-            tree.update_inner_node(1, *idx / 2, new_hash.clone());
-            drop(tree);
-            next_row.push((*idx / 2, prev_res_hash.clone()));
-
-            // * Preimages -----------------------------------------------------------------------------------------------
-
-            // ? Insert the new hash info into the preimage
-            let mut preimage = preimage_mutex.lock();
-
-            if !preimage.contains_key(&prev_res_hash.to_string()) {
-                preimage.insert(
-                    prev_res_hash.to_string(),
-                    serde_json::to_value([prev_left_hash.to_string(), prev_right_hash.to_string()])
-                        .unwrap(),
-                );
-            }
-
-            preimage.insert(
-                new_hash.to_string(),
-                serde_json::to_value([left_hash.to_string(), hash.to_string()]).unwrap(),
-            );
-            drop(preimage);
-
-            // * Preimages -----------------------------------------------------------------------------------------------
-        }
+    pub fn with_sequential_threshold(mut self, sequential_threshold: usize) -> TreeConfig {
+        self.sequential_threshold = sequential_threshold;
+        self
     }
 
-    return next_row;
-}
+    /// Sets the maximum number of partition trees updated at the same time (at least 1).
+    pub fn with_partitions_in_flight(mut self, partitions_in_flight: usize) -> TreeConfig {
+        self.partitions_in_flight = partitions_in_flight.max(1);
+        self
+    }
 
-fn build_next_row(
-    tree_mutex: &Arc<Mutex<&mut Tree>>,
-    preimage_mutex: &Arc<Mutex<&mut Map<String, Value>>>,
-    entries: Vec<(&u64, &String)>, // 4 entries taken from the hashmap to be updated in parallel
-    hashes: &HashMap<u64, String>, // the whole hashmap
-    row_depth: usize,
-) -> Vec<(u64, String)> {
-    // next row stores the indexes of the next row that need to be updated
-    // (and the previous result hashes for the init state preimage)
-    let mut next_row: Vec<(u64, String)> = Vec::new();
-
-    for (idx, prev_res) in entries.iter() {
-        // ! Left child
-        if *idx % 2 == 0 {
-            //? If the right child exists, hash them together in the next loop
-            if hashes.get(&(*idx + 1)).is_some() {
-                continue;
-            }
-            //? If the right child doesn't exist (hasn't been updated), hash the left child with the previous value in the state tree
-            else {
-                // ? Get the previous values from the state tree
-                let tree = tree_mutex.lock();
-                let hash = &tree.ith_inner_node(row_depth as u32, **idx);
-                let right_hash = &tree.ith_inner_node(row_depth as u32, *idx + 1);
-                drop(tree);
-
-                // ? Hash the left child with the right child
-                let new_hash = pedersen(hash, right_hash);
-
-                // ? Use the new_hash to update the merkle tree
-                let mut tree = tree_mutex.lock();
-                let prev_res_hash = tree.ith_inner_node(row_depth as u32 + 1, *idx / 2);
-                tree.update_inner_node(row_depth as u32 + 1, *idx / 2, new_hash.clone());
-                drop(tree);
-                next_row.push((*idx / 2, prev_res_hash.clone()));
-
-                // * Preimages -----------------------------------------------------------------------------------------------
-
-                // ? Insert the new hash info into the preimage
-                let mut preimage = preimage_mutex.lock();
-
-                // ? Previous batch state preimage
-                if !preimage.contains_key(&prev_res_hash.to_string()) {
-                    preimage.insert(
-                        prev_res_hash.to_string(),
-                        serde_json::to_value([prev_res.to_string(), right_hash.to_string()])
-                            .unwrap(),
-                    );
-                }
-
-                // ? Current batch state preimage
-                preimage.insert(
-                    new_hash.to_string(),
-                    serde_json::to_value([hash.to_string(), right_hash.to_string()]).unwrap(),
-                );
-                drop(preimage);
-
-                // * Preimages -----------------------------------------------------------------------------------------------
-            }
-        }
-        // ! Right child
-        else {
-            // ? Get the left and right hashes
-            let tree = tree_mutex.lock();
-
-            let hash = &tree.ith_inner_node(row_depth as u32, **idx);
-            let left_hash = &tree.ith_inner_node(row_depth as u32, *idx - 1);
-            let prev_left_hash: String;
-            if let Some(prev_left) = hashes.get(&(*idx - 1)) {
-                prev_left_hash = prev_left.clone();
-            } else {
-                prev_left_hash = left_hash.clone();
-            }
-            let prev_right_hash = *prev_res;
-
-            drop(tree);
-
-            // ? Hash the left child with the right child
-            let new_hash = pedersen(&left_hash, &hash);
-
-            // ? Use the new_hash to update the merkle tree
-            let mut tree = tree_mutex.lock();
-            let prev_res_hash = tree.ith_inner_node(row_depth as u32 + 1, *idx / 2);
-            tree.update_inner_node(row_depth as u32 + 1, *idx / 2, new_hash.clone());
-            drop(tree);
-
-            next_row.push((*idx / 2, prev_res_hash.clone()));
-
-            // * Preimages -----------------------------------------------------------------------------------------------
-
-            // ? Insert the new hash info into the preimage
-            let mut preimage = preimage_mutex.lock();
-
-            // ? Previous batch state preimage
-            if !preimage.contains_key(&prev_res_hash.to_string()) {
-                preimage.insert(
-                    prev_res_hash.to_string(),
-                    serde_json::to_value([prev_left_hash.to_string(), prev_right_hash.to_string()])
-                        .unwrap(),
-                );
-            }
-
-            // ? Current batch state preimage
-            preimage.insert(
-                new_hash.to_string(),
-                serde_json::to_value([left_hash.to_string(), hash.to_string()]).unwrap(),
-            );
-            drop(preimage);
-
-            // * Preimages -----------------------------------------------------------------------------------------------
+    /// Sets the maximum number of versions a tree keeps in memory (see `Tree::get_proof_at`).
+    ///
+    /// Like `Retention::Batches` for the batch backups: a tree opened from a node store is persisted
+    /// once it has more versions than that, any other tree drops its oldest version.
+    /// With 0 (the default) the tree keeps no history and only its latest version can be read.
+    pub fn with_history_limit(mut self, history_limit: usize) -> TreeConfig {
+        self.history_limit = history_limit;
+        self
+    }
+
+    /// Processes everything on the calling thread.
+    pub fn sequential(self) -> TreeConfig {
+        self.with_sequential_threshold(usize::MAX)
+    }
+
+    /// Whether `len` nodes are processed on the calling thread
+    pub fn is_sequential(&self, len: usize) -> bool {
+        len < self.sequential_threshold
+    }
+
+    /// Runs `op` on the configured thread pool (its parallel iterators use that pool).
+    pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.thread_pool {
+            Some(thread_pool) => thread_pool.install(op),
+            None => op(),
         }
     }
+}
 
-    return next_row;
+impl Default for TreeConfig {
+    fn default() -> Self {
+        TreeConfig {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            thread_pool: None,
+            sequential_threshold: DEFAULT_SEQUENTIAL_THRESHOLD,
+            partitions_in_flight: DEFAULT_PARTITIONS_IN_FLIGHT,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
 }
 
-pub fn build_tree(depth: u32, leaf_nodes: &Vec<String>, shift: u32) -> String {
-    let inner_nodes: Vec<Vec<String>> = inner_from_leaf_nodes(depth as usize, leaf_nodes, shift);
-    let root = inner_nodes[0][0].clone();
+/// An updated parent node: (idx, new hash, new children, (previous hash, previous children) if `with_prev`)
+pub type UpdatedNode<N> = (u64, N, [N; 2], Option<(N, [N; 2])>);
+
+/// Computes the parents (at `level + 1`) of the updated nodes of a level in parallel.
+///
+/// `row` holds the updated nodes of the level sorted by index, their siblings that were not updated
+/// (and the previous nodes if `with_prev`) are read from the tree, which is only borrowed immutably
+/// so the parents are hashed without taking any lock (on the thread pool of the tree's `TreeConfig`).
+/// The parents are returned sorted by index.
+pub fn compute_parent_row<H: MerkleHasher>(
+    tree: &Tree<H>,
+    level: u32,
+    row: &[(u64, H::Node)],
+    with_prev: bool,
+) -> Result<Vec<UpdatedNode<H::Node>>, MerkleError> {
+    let mut parents: Vec<u64> = row.iter().map(|(idx, _)| idx / 2).collect();
+    parents.dedup();
+
+    let node = |idx: u64| match row.binary_search_by_key(&idx, |(idx, _)| *idx) {
+        Ok(pos) => Ok(row[pos].1),
+        Err(_) => tree.node(level, idx),
+    };
+
+    let parent_node = |parent: u64| {
+        let children = [node(2 * parent)?, node(2 * parent + 1)?];
+        let hash = H::hash(&children[0], &children[1]);
+
+        let prev = if with_prev {
+            let prev_children = [
+                tree.node(level, 2 * parent)?,
+                tree.node(level, 2 * parent + 1)?,
+            ];
+            Some((tree.node(level + 1, parent)?, prev_children))
+        } else {
+            None
+        };
+
+        Ok((parent, hash, children, prev))
+    };
+
+    let config = &tree.config;
+    if config.is_sequential(parents.len()) {
+        return parents.into_iter().map(parent_node).collect();
+    }
 
-    return root;
+    config.install(|| {
+        parents
+            .into_par_iter()
+            .with_min_len(config.chunk_size)
+            .map(parent_node)
+            .collect()
+    })
 }
 
-fn inner_from_leaf_nodes(depth: usize, leaf_nodes: &Vec<String>, shift: u32) -> Vec<Vec<String>> {
-    let mut tree: Vec<Vec<String>> = Vec::new();
+/// The root of the tree of depth `depth` with `leaf_nodes` as its first leaves (the others are zero leaves).
+pub fn build_tree<H: MerkleHasher>(
+    depth: u32,
+    leaf_nodes: &Vec<H::Node>,
+    shift: u32,
+    config: &TreeConfig,
+) -> Result<H::Node, MerkleError> {
+    if leaf_nodes.is_empty() {
+        return Err(MerkleError::NoLeaves);
+    }
+    if depth < 64 && (leaf_nodes.len() as u64 - 1) >> depth != 0 {
+        return Err(MerkleError::IndexOutOfRange {
+            level: 0,
+            idx: leaf_nodes.len() as u64 - 1,
+            depth,
+        });
+    }
+
+    // ? A tree of depth 0 is a single leaf
+    if depth == 0 {
+        return Ok(leaf_nodes[0]);
+    }
+
+    let inner_nodes: Vec<Vec<H::Node>> =
+        inner_from_leaf_nodes::<H>(depth as usize, leaf_nodes, shift, config)?;
+    let root = inner_nodes[0][0];
+
+    return Ok(root);
+}
 
-    let first_row = leaf_nodes;
+fn inner_from_leaf_nodes<H: MerkleHasher>(
+    depth: usize,
+    leaf_nodes: &Vec<H::Node>,
+    shift: u32,
+    config: &TreeConfig,
+) -> Result<Vec<Vec<H::Node>>, MerkleError> {
+    let mut tree: Vec<Vec<H::Node>> = Vec::new();
 
-    let len = leaf_nodes.len();
-    let new_len = if len % 2 == 0 { len / 2 } else { len / 2 + 1 };
-    let mut hashes: Vec<String> = vec!["0".to_string(); new_len];
-    let hashes_mutex = Arc::new(Mutex::new(&mut hashes));
-    hash_tree_level(&hashes_mutex, &first_row, 0, 0, shift);
-    tree.push(hashes);
+    let first_row = hash_tree_level::<H>(leaf_nodes, 0, shift, config)?;
+    tree.push(first_row);
 
     for i in 1..depth {
-        let len = &tree[i - 1].len();
-        let new_len = if len % 2 == 0 { len / 2 } else { len / 2 + 1 };
-        let mut hashes: Vec<String> = vec!["0".to_string(); new_len];
-        let hashes_mutex = Arc::new(Mutex::new(&mut hashes));
-        hash_tree_level(&hashes_mutex, &tree[i - 1], i, 0, shift);
-        tree.push(hashes);
+        let next_row = hash_tree_level::<H>(&tree[i - 1], i, shift, config)?;
+        tree.push(next_row);
     }
 
     tree.reverse();
-    return tree;
+    return Ok(tree);
 }
 
-fn hash_tree_level(
-    next_row: &Arc<Mutex<&mut Vec<String>>>,
-    leaf_nodes: &Vec<String>,
+/// Hashes the nodes of a level pairwise into the next level, `config.chunk_size` nodes per task
+fn hash_tree_level<H: MerkleHasher>(
+    row: &[H::Node],
     i: usize,
-    n: usize,
     shift: u32,
-) {
-    let inp_array = leaf_nodes
-        .iter()
-        .skip(n * STRIDE)
-        .take(STRIDE)
-        .collect::<Vec<&String>>();
-
-    // println!("inp_array: {:?}", inp_array);
-
-    if inp_array.len() > 0 {
-        rayon::join(
-            || {
-                let next_row_hashes = pairwise_hash(&inp_array, i, shift);
-                let mut next_hashes = next_row.lock();
-
-                let hashes_len = next_hashes.len();
-                if hashes_len < (n * STRIDE) / 2 + STRIDE / 2 || next_row_hashes.len() < STRIDE / 2
-                {
-                    next_hashes.as_mut_slice()[(n * STRIDE) / 2..]
-                        .clone_from_slice(&next_row_hashes);
-                } else {
-                    next_hashes.as_mut_slice()[(n * STRIDE) / 2..(n * STRIDE) / 2 + STRIDE / 2]
-                        .clone_from_slice(&next_row_hashes);
-                }
-
-                drop(next_hashes);
-            },
-            || hash_tree_level(next_row, leaf_nodes, i, n + 1, shift),
-        );
-    }
+    config: &TreeConfig,
+) -> Result<Vec<H::Node>, MerkleError> {
+    let hash_chunk = |chunk: &[H::Node]| {
+        let chunk = chunk.iter().collect::<Vec<&H::Node>>();
+        pairwise_hash::<H>(&chunk, i, shift)
+    };
+
+    // ? The chunks are even so that no pair is split between two chunks
+    let chunks: Vec<Vec<H::Node>> = if config.is_sequential(row.len()) {
+        row.chunks(config.chunk_size)
+            .map(hash_chunk)
+            .collect::<Result<_, MerkleError>>()?
+    } else {
+        config.install(|| {
+            row.par_chunks(config.chunk_size)
+                .map(hash_chunk)
+                .collect::<Result<_, MerkleError>>()
+        })?
+    };
+
+    return Ok(chunks.concat());
 }
 
-pub fn pairwise_hash(array: &Vec<&String>, i: usize, shift: u32) -> Vec<String> {
-    // This should be an array of STRIDE length
+pub fn pairwise_hash<H: MerkleHasher>(
+    array: &Vec<&H::Node>,
+    i: usize,
+    shift: u32,
+) -> Result<Vec<H::Node>, MerkleError> {
+    // This should be an array of `TreeConfig::chunk_size` length
 
-    let mut hashes: Vec<String> = Vec::new();
-    for j in (0..array.len() - 1).step_by(2) {
-        let hash = pedersen(&array[j], &array[j + 1]);
+    let mut hashes: Vec<H::Node> = Vec::new();
+    for j in (1..array.len()).step_by(2) {
+        let hash = H::hash(&array[j - 1], &array[j]);
         hashes.push(hash);
     }
 
     if array.len() % 2 == 1 {
-        hashes.push(pedersen(
+        hashes.push(H::hash(
             &array[array.len() - 1],
-            &get_zero_hash(i as u32, shift),
+            &get_zero_hash::<H>(i as u32, shift)?,
         ));
     }
 
-    return hashes;
+    return Ok(hashes);
 }
@@ -0,0 +1,332 @@
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+
+use serde::{de, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use starknet_crypto::FieldElement;
+
+use super::{
+    errors::MerkleError,
+    hashers::{MerkleHasher, MerkleNode},
+};
+
+/// The children of every node hashed by one or more batches {hash: [left, right]},
+/// covering the paths of the updated leaves in both the previous and the new tree.
+///
+/// It is (de)serialized in the json format expected by the prover, where every hash
+/// and child is a string (`{"hash": ["left", "right"]}`, decimal for field elements).
+///
+/// The hashes are kept sorted, so the same batch always iterates and serializes the same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preimage<N: MerkleNode = FieldElement> {
+    nodes: BTreeMap<N, [N; 2]>,
+}
+
+impl<N: MerkleNode> Preimage<N> {
+    pub fn new() -> Preimage<N> {
+        Preimage {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Inserts the children of `hash`, returning the children it had before (if any).
+    pub fn insert(&mut self, hash: N, children: [N; 2]) -> Option<[N; 2]> {
+        self.nodes.insert(hash, children)
+    }
+
+    /// Inserts the children of `hash` unless it already has children.
+    pub fn insert_if_absent(&mut self, hash: N, children: [N; 2]) {
+        self.nodes.entry(hash).or_insert(children);
+    }
+
+    /// The children of `hash`.
+    pub fn get(&self, hash: &N) -> Option<&[N; 2]> {
+        self.nodes.get(hash)
+    }
+
+    pub fn contains(&self, hash: &N) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    /// The number of hashes in the preimage.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Iterates over the hashes in ascending order.
+    pub fn iter(&self) -> btree_map::Iter<'_, N, [N; 2]> {
+        self.nodes.iter()
+    }
+
+    /// Adds all the hashes of `other` (e.g. the preimage of another partition or batch).
+    pub fn merge(&mut self, other: Preimage<N>) {
+        if self.nodes.len() < other.nodes.len() {
+            let nodes = std::mem::replace(&mut self.nodes, other.nodes);
+            self.nodes.extend(nodes);
+        } else {
+            self.nodes.extend(other.nodes);
+        }
+    }
+
+    /// Checks that every hash is the hash of its children.
+    pub fn verify<H: MerkleHasher<Node = N>>(&self) -> bool {
+        self.invalid_hashes::<H>().is_empty()
+    }
+
+    /// The hashes that are not the hash of their children.
+    pub fn invalid_hashes<H: MerkleHasher<Node = N>>(&self) -> Vec<N> {
+        self.nodes
+            .iter()
+            .filter(|(hash, [left, right])| H::hash(left, right) != **hash)
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    /// Checks that the preimage links `prev_root` to `new_root` for a batch that updated the leaves
+    /// of `updated_hashes` in a tree of depth `depth`, and returns all the problems it finds
+    /// (an empty list if the preimage is complete and consistent).
+    ///
+    /// Both trees are walked from their root down to the updated leaves: every node on the way needs an entry
+    /// that hashes to it, the new tree has to end in the updated leaves and every sibling off the updated paths
+    /// has to be the same in both trees.
+    pub fn check_transition<H: MerkleHasher<Node = N>>(
+        &self,
+        prev_root: &N,
+        new_root: &N,
+        updated_hashes: &HashMap<u64, N>,
+        depth: u32,
+    ) -> Result<Vec<PreimageIssue<N>>, MerkleError> {
+        for idx in updated_hashes.keys() {
+            if depth < 64 && idx >> depth != 0 {
+                return Err(MerkleError::IndexOutOfRange {
+                    level: 0,
+                    idx: *idx,
+                    depth,
+                });
+            }
+        }
+
+        // ? The indices of the nodes on the paths of the updated leaves at every level
+        let paths = (0..=depth)
+            .map(|level| {
+                updated_hashes
+                    .keys()
+                    .map(|idx| idx.checked_shr(level).unwrap_or(0))
+                    .collect::<HashSet<u64>>()
+            })
+            .collect::<Vec<HashSet<u64>>>();
+
+        let mut issues: Vec<PreimageIssue<N>> = Vec::new();
+        let (_, prev_siblings) =
+            self.walk::<H>(TreeSide::Previous, prev_root, depth, &paths, &mut issues);
+        let (new_leaves, new_siblings) =
+            self.walk::<H>(TreeSide::New, new_root, depth, &paths, &mut issues);
+
+        let mut updated_leaves = updated_hashes.iter().collect::<Vec<_>>();
+        updated_leaves.sort_unstable_by_key(|(idx, _)| **idx);
+        for (idx, expected) in updated_leaves {
+            if let Some(found) = new_leaves.get(idx) {
+                if found != expected {
+                    issues.push(PreimageIssue::LeafMismatch {
+                        idx: *idx,
+                        expected: *expected,
+                        found: *found,
+                    });
+                }
+            }
+        }
+
+        for ((level, idx), prev) in prev_siblings.iter() {
+            if let Some(new) = new_siblings.get(&(*level, *idx)) {
+                if prev != new {
+                    issues.push(PreimageIssue::SiblingMismatch {
+                        level: *level,
+                        idx: *idx,
+                        prev: *prev,
+                        new: *new,
+                    });
+                }
+            }
+        }
+
+        Ok(issues)
+    }
+
+    /// Walks the tree from the root down the paths, returns the reached leaves and the siblings off the paths
+    fn walk<H: MerkleHasher<Node = N>>(
+        &self,
+        side: TreeSide,
+        root: &N,
+        depth: u32,
+        paths: &[HashSet<u64>],
+        issues: &mut Vec<PreimageIssue<N>>,
+    ) -> (BTreeMap<u64, N>, BTreeMap<(u32, u64), N>) {
+        let mut nodes: BTreeMap<u64, N> = BTreeMap::new();
+        nodes.insert(0, *root);
+
+        let mut siblings: BTreeMap<(u32, u64), N> = BTreeMap::new();
+        for level in (1..=depth).rev() {
+            let mut children: BTreeMap<u64, N> = BTreeMap::new();
+
+            for (idx, hash) in nodes.iter() {
+                let [left, right] = match self.get(hash) {
+                    Some(pair) => pair,
+                    None => {
+                        issues.push(PreimageIssue::Missing {
+                            side,
+                            level,
+                            idx: *idx,
+                            hash: *hash,
+                        });
+                        continue;
+                    }
+                };
+
+                if H::hash(left, right) != *hash {
+                    issues.push(PreimageIssue::Inconsistent {
+                        side,
+                        level,
+                        idx: *idx,
+                        hash: *hash,
+                    });
+                }
+
+                for (child_idx, child) in [(2 * idx, left), (2 * idx + 1, right)] {
+                    if paths[level as usize - 1].contains(&child_idx) {
+                        children.insert(child_idx, *child);
+                    } else {
+                        siblings.insert((level - 1, child_idx), *child);
+                    }
+                }
+            }
+
+            nodes = children;
+        }
+
+        return (nodes, siblings);
+    }
+
+    /// Converts the preimage into the json format expected by the prover {hash: [left, right]}
+    pub fn to_json(&self) -> Map<String, Value> {
+        let mut json_map: Map<String, Value> = Map::new();
+
+        for (hash, [left, right]) in self.nodes.iter() {
+            json_map.insert(
+                hash.to_string(),
+                Value::Array(vec![
+                    Value::String(left.to_string()),
+                    Value::String(right.to_string()),
+                ]),
+            );
+        }
+
+        return json_map;
+    }
+
+    /// Parses a preimage in the json format of `to_json`.
+    pub fn from_json(json_map: &Map<String, Value>) -> Result<Preimage<N>, MerkleError> {
+        let mut preimage = Preimage::new();
+
+        for (hash, children) in json_map.iter() {
+            let children = match children.as_array().map(|children| &children[..]) {
+                Some([Value::String(left), Value::String(right)]) => {
+                    [N::from_str(left)?, N::from_str(right)?]
+                }
+                _ => {
+                    return Err(MerkleError::Serialization(format!(
+                        "the preimage of {} is not a pair of strings",
+                        hash
+                    )))
+                }
+            };
+
+            preimage.insert(N::from_str(hash)?, children);
+        }
+
+        Ok(preimage)
+    }
+}
+
+impl<N: MerkleNode> Default for Preimage<N> {
+    fn default() -> Self {
+        Preimage::new()
+    }
+}
+
+impl<N: MerkleNode> From<HashMap<N, [N; 2]>> for Preimage<N> {
+    fn from(nodes: HashMap<N, [N; 2]>) -> Self {
+        Preimage {
+            nodes: nodes.into_iter().collect(),
+        }
+    }
+}
+
+impl<N: MerkleNode> From<BTreeMap<N, [N; 2]>> for Preimage<N> {
+    fn from(nodes: BTreeMap<N, [N; 2]>) -> Self {
+        Preimage { nodes }
+    }
+}
+
+impl<N: MerkleNode> Serialize for Preimage<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.nodes.len()))?;
+        for (hash, [left, right]) in self.nodes.iter() {
+            map.serialize_entry(&hash.to_string(), &[left.to_string(), right.to_string()])?;
+        }
+
+        map.end()
+    }
+}
+
+impl<'de, N: MerkleNode> Deserialize<'de> for Preimage<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let nodes = HashMap::<String, [String; 2]>::deserialize(deserializer)?;
+
+        let parse = |value: &String| N::from_str(value).map_err(de::Error::custom);
+
+        let mut preimage = Preimage::new();
+        for (hash, [left, right]) in nodes.iter() {
+            preimage.insert(parse(hash)?, [parse(left)?, parse(right)?]);
+        }
+
+        Ok(preimage)
+    }
+}
+
+/// The tree of a transition a `PreimageIssue` was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeSide {
+    Previous,
+    New,
+}
+
+/// A problem found by `Preimage::check_transition`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreimageIssue<N: MerkleNode = FieldElement> {
+    /// A node on the path of an updated leaf has no entry (level 0 are the leaves)
+    Missing {
+        side: TreeSide,
+        level: u32,
+        idx: u64,
+        hash: N,
+    },
+    /// The children of a node do not hash to the node
+    Inconsistent {
+        side: TreeSide,
+        level: u32,
+        idx: u64,
+        hash: N,
+    },
+    /// The new tree does not end in the updated leaf
+    LeafMismatch { idx: u64, expected: N, found: N },
+    /// A node off the updated paths differs between the previous and the new tree
+    SiblingMismatch {
+        level: u32,
+        idx: u64,
+        prev: N,
+        new: N,
+    },
+}
@@ -0,0 +1,183 @@
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use super::{
+    errors::MerkleError,
+    hashers::{MerkleHasher, PedersenHasher},
+    tree_utils::{batch_proof_pos, get_zero_hash},
+};
+
+/// A merkle inclusion proof for a single leaf of a `Tree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof<H: MerkleHasher = PedersenHasher> {
+    pub leaf_idx: u64,
+    pub leaf_hash: H::Node,
+    /// The sibling hashes from the leaf level up to (but excluding) the root
+    pub path: Vec<H::Node>,
+    /// 0 if the node on the path is a left child at that level, 1 if it is a right child
+    pub directions: Vec<i8>,
+    pub depth: u32,
+    pub shift: u32,
+    pub hasher: PhantomData<H>,
+}
+
+impl<H: MerkleHasher> MerkleProof<H> {
+    /// Recomputes the root from the leaf hash and the sibling path and checks it against `root`.
+    ///
+    /// Missing siblings (a path shorter than the depth) are treated as empty subtrees
+    /// and padded with the zero hash of that level.
+    pub fn verify(&self, root: &H::Node) -> bool {
+        if self.directions.len() != self.depth as usize || self.path.len() > self.depth as usize {
+            return false;
+        }
+
+        // ? The leaf has to be in the tree and the directions have to match its position
+        if self.directions.len() < 64 && self.leaf_idx >> self.directions.len() != 0 {
+            return false;
+        }
+        for i in 0..self.depth as usize {
+            if self.directions[i] as u64 != (self.leaf_idx >> i) & 1 {
+                return false;
+            }
+        }
+
+        match self.compute_root() {
+            Ok(computed_root) => &computed_root == root,
+            Err(_) => false,
+        }
+    }
+
+    /// Hashes the leaf up the tree using the sibling path and returns the resulting root.
+    pub fn compute_root(&self) -> Result<H::Node, MerkleError> {
+        let mut hash = self.leaf_hash;
+
+        for i in 0..self.depth as usize {
+            let sibling = match self.path.get(i) {
+                Some(sibling) => *sibling,
+                None => get_zero_hash::<H>(i as u32, self.shift)?,
+            };
+
+            if self.directions.get(i).copied().unwrap_or(0) == 0 {
+                hash = H::hash(&hash, &sibling);
+            } else {
+                hash = H::hash(&sibling, &hash);
+            }
+        }
+
+        return Ok(hash);
+    }
+}
+
+/// A merkle proof for multiple leaves of the same `Tree`.
+///
+/// Siblings shared by several leaves (and siblings that are themselves on the path
+/// of another proven leaf) are only included once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchMerkleProof<H: MerkleHasher = PedersenHasher> {
+    /// The proven leaves as (leaf_idx, leaf_hash), sorted by index
+    pub leaves: Vec<(u64, H::Node)>,
+    /// The sibling hashes in the order given by `batch_proof_pos` (level by level, ascending index)
+    pub siblings: Vec<H::Node>,
+    pub depth: u32,
+    pub shift: u32,
+    pub hasher: PhantomData<H>,
+}
+
+impl<H: MerkleHasher> BatchMerkleProof<H> {
+    /// Recomputes the root from the leaves and the siblings and checks it against `root`.
+    pub fn verify(&self, root: &H::Node) -> bool {
+        match self.compute_root() {
+            Some(computed_root) => &computed_root == root,
+            None => false,
+        }
+    }
+
+    /// Hashes all the leaves up the tree and returns the resulting root.
+    ///
+    /// Returns `None` if the proof is malformed (no leaves, unsorted or out of range
+    /// leaf indices, or a sibling count that does not match the leaves).
+    pub fn compute_root(&self) -> Option<H::Node> {
+        if self.leaves.is_empty() {
+            return None;
+        }
+
+        let mut nodes: BTreeMap<u64, H::Node> = BTreeMap::new();
+        for (i, (idx, hash)) in self.leaves.iter().enumerate() {
+            if i > 0 && self.leaves[i - 1].0 >= *idx {
+                return None;
+            }
+            if self.depth < 64 && *idx >= 1 << self.depth {
+                return None;
+            }
+
+            nodes.insert(*idx, *hash);
+        }
+
+        let leaf_indices: Vec<u64> = self.leaves.iter().map(|(idx, _)| *idx).collect();
+        let proof_pos = batch_proof_pos(&leaf_indices, self.depth as usize);
+
+        let mut siblings = self.siblings.iter();
+        for level_pos in proof_pos.iter() {
+            for pos in level_pos {
+                nodes.insert(*pos, *siblings.next()?);
+            }
+
+            // ? Every node now has its sibling in the map, so hash them pairwise
+            let mut next_nodes: BTreeMap<u64, H::Node> = BTreeMap::new();
+            let mut entries = nodes.iter();
+            while let Some((idx, left)) = entries.next() {
+                let (_, right) = entries.next()?;
+
+                next_nodes.insert(idx / 2, H::hash(left, right));
+            }
+
+            nodes = next_nodes;
+        }
+
+        if siblings.next().is_some() {
+            return None;
+        }
+
+        return nodes.remove(&0);
+    }
+}
+
+/// The witness of a single leaf update, the batch updates of `Tree::batch_transition_witnesses`
+/// are applied one after the other in the order of their witnesses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateWitness<H: MerkleHasher = PedersenHasher> {
+    pub leaf_idx: u64,
+    pub old_leaf: H::Node,
+    pub new_leaf: H::Node,
+    /// The sibling hashes from the leaf level up to (but excluding) the root, the same before and after the update
+    pub path: Vec<H::Node>,
+    /// The root after this update (and all the updates before it)
+    pub new_root: H::Node,
+    pub hasher: PhantomData<H>,
+}
+
+impl<H: MerkleHasher> UpdateWitness<H> {
+    /// Checks that the path links the old leaf to `prev_root` (the root after the previous update)
+    /// and the new leaf to the new root.
+    pub fn verify(&self, prev_root: &H::Node) -> bool {
+        if self.path.len() < 64 && self.leaf_idx >> self.path.len() != 0 {
+            return false;
+        }
+
+        return &self.compute_root(&self.old_leaf) == prev_root
+            && self.compute_root(&self.new_leaf) == self.new_root;
+    }
+
+    fn compute_root(&self, leaf: &H::Node) -> H::Node {
+        let mut hash = *leaf;
+
+        for (i, sibling) in self.path.iter().enumerate() {
+            if (self.leaf_idx >> i) & 1 == 0 {
+                hash = H::hash(&hash, sibling);
+            } else {
+                hash = H::hash(sibling, &hash);
+            }
+        }
+
+        return hash;
+    }
+}
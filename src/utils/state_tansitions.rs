@@ -1,71 +1,392 @@
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
-use serde_json::{Map, Value};
+use rayon::prelude::{
+    IntoParallelIterator, IntoParallelRefIterator, ParallelIterator, ParallelSliceMut,
+};
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use std::error::Error;
 use std::result::Result;
 
-use crate::Tree;
+use crate::{
+    utils::{
+        errors::{MerkleError, MAX_TREE_DEPTH},
+        hashers::{MerkleHasher, MerkleNode},
+        node_store::{FileNodeStore, NodeStore, TreeUpdate},
+        parallelization::TreeConfig,
+        preimage::Preimage,
+        proofs::MerkleProof,
+        storage::{backup_tree, clear_batch_backup, read_version, recover_storage, StorageConfig},
+    },
+    Tree,
+};
+
+/// The updated leaves of every partition as (partition_index, [(idx, new_hash)]) sorted by index
+type Partitions<N> = Vec<(u32, Vec<(u64, N)>)>;
+
+/// (partition_index, new root, preimage) of an updated partition
+type PartitionUpdate<N> = (u32, N, Preimage<N>);
+
+/// (previous root, new root, updated nodes) of a tree updated by a batch
+type TreeTransition<N> = (N, N, TreeUpdate<N>);
 
 /// This functions fetches all the merkle trees from storage and updates them and stores the updated trees back to storage.
 /// This allows the main merkle tree to be broken up into smaller trees that can be updated in parallel
 /// and requires significantly less memory to update.
 ///
+/// Every touched tree is copied to the backup folder of the batch before it is updated, so if the batch fails
+/// `storage::restore_backup` can be used to roll all the trees back to their previous state
+/// (the backups are kept until they are removed by `storage::collect_garbage`).
+///
+/// The partitions are loaded, updated and staged in parallel, at most `TreeConfig::partitions_in_flight`
+/// at a time to cap the memory: a staged partition only lives on disk (see `FileNodeStore`) and the
+/// preimages of every wave of partitions are merged before the next wave is loaded.
+///
+/// The updated trees are committed atomically (see `storage::commit_staged`), after a crash either
+/// the whole batch or none of it is visible once `storage::recover_storage` has run.
+///
+/// Every call produces the next version of the state tree (the version of the root tree `u32::MAX`),
+/// which can be queried afterwards with `state_root_at` and `get_state_proof_at`.
+///
+/// The partitions are updated in ascending order, so the same updates always give the same trees and a
+/// byte-identical preimage.
+///
 /// # Arguments
 ///
+/// * `config` - where the trees are stored (see `StorageConfig`)
 /// * `updated_state_hashes` - The hashmap of all the leaf nodes that need to be updated {idx: new_hash}
 /// * `total_depth` - the total depth of the main merkle tree (this can be spilt up into shallower trees of depth `partition_size_exponent`)
-pub fn update_trees(
+/// * `partition_size_exponent` - the depth of the partitions, at most 2^31 of them fit in the root tree (`MerkleError::InvalidDepth` otherwise)
+pub fn update_trees<H: MerkleHasher>(
+    config: &StorageConfig,
     updated_state_hashes: HashMap<u64, String>,
     total_depth: u32,
     partition_size_exponent: u32,
-) -> Result<(String, String, Map<String, Value>), Box<dyn Error>> {
-    // * UPDATE SPOT TREES  -------------------------------------------------------------------------------------
-    let mut updated_root_hashes: HashMap<u64, String> = HashMap::new(); // the new roots of all tree partitions
+) -> Result<(String, String, Preimage<H::Node>), MerkleError> {
+    update_trees_with_config::<H>(
+        config,
+        &TreeConfig::default(),
+        updated_state_hashes,
+        total_depth,
+        partition_size_exponent,
+    )
+}
+
+/// Same as `update_trees` with the work parallelized as set in `tree_config`
+/// (e.g. on a dedicated thread pool).
+pub fn update_trees_with_config<H: MerkleHasher>(
+    config: &StorageConfig,
+    tree_config: &TreeConfig,
+    updated_state_hashes: HashMap<u64, String>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+) -> Result<(String, String, Preimage<H::Node>), MerkleError> {
+    // ? Finish (or discard) a batch that was interrupted by a crash before reading any tree
+    recover_storage(config)?;
 
-    let mut preimage_json: Map<String, Value> = Map::new();
+    // ? Drop the backups of an earlier attempt at the same batch that was never committed
+    let version = read_version(config, u32::MAX)? + 1;
+    clear_batch_backup(config, version)?;
 
-    let partitioned_hashes = split_hashmap(
+    let partitioned_hashes = parse_and_split(
         updated_state_hashes,
-        2_usize.pow(partition_size_exponent) as usize,
-    );
+        total_depth,
+        partition_size_exponent,
+        tree_config,
+    )?;
+
+    // ? Back up the current trees before they are updated
+    tree_config.install(|| {
+        partitioned_hashes
+            .par_iter()
+            .try_for_each(|(partition_index, _)| backup_tree(config, version, *partition_index))
+    })?;
+    backup_tree(config, version, u32::MAX)?;
+
+    let store: Arc<dyn NodeStore<H::Node>> = Arc::new(FileNodeStore::new(config.clone()));
+
+    update_partitions::<H>(
+        &store,
+        tree_config,
+        partitioned_hashes,
+        total_depth,
+        partition_size_exponent,
+    )
+}
 
-    // ? Loop over all partitions and update the trees
+/// Same as `update_trees` but the trees are read lazily from and written to any `NodeStore`
+/// (without the backups of the filesystem storage).
+pub fn update_trees_in_store<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
+    updated_state_hashes: HashMap<u64, String>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+) -> Result<(String, String, Preimage<H::Node>), MerkleError> {
+    update_trees_in_store_with_config::<H>(
+        store,
+        &TreeConfig::default(),
+        updated_state_hashes,
+        total_depth,
+        partition_size_exponent,
+    )
+}
+
+/// Same as `update_trees_in_store` with the work parallelized as set in `tree_config`.
+pub fn update_trees_in_store_with_config<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
+    tree_config: &TreeConfig,
+    updated_state_hashes: HashMap<u64, String>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+) -> Result<(String, String, Preimage<H::Node>), MerkleError> {
+    let partitioned_hashes = parse_and_split(
+        updated_state_hashes,
+        total_depth,
+        partition_size_exponent,
+        tree_config,
+    )?;
+
+    update_partitions::<H>(
+        store,
+        tree_config,
+        partitioned_hashes,
+        total_depth,
+        partition_size_exponent,
+    )
+}
+
+/// Computes the new root of the state tree `update_trees` would produce for the updates
+/// (and fills the same preimage if one is given), without changing any stored tree.
+pub fn compute_root_after_update<H: MerkleHasher>(
+    config: &StorageConfig,
+    updated_state_hashes: &HashMap<u64, String>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+    preimage: Option<&mut Preimage<H::Node>>,
+) -> Result<String, MerkleError> {
+    let store: Arc<dyn NodeStore<H::Node>> = Arc::new(FileNodeStore::new(config.clone()));
+
+    compute_root_after_update_in_store::<H>(
+        &store,
+        updated_state_hashes,
+        total_depth,
+        partition_size_exponent,
+        preimage,
+    )
+}
+
+/// Same as `compute_root_after_update` for the trees in any `NodeStore`.
+pub fn compute_root_after_update_in_store<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
+    updated_state_hashes: &HashMap<u64, String>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+    mut preimage: Option<&mut Preimage<H::Node>>,
+) -> Result<String, MerkleError> {
+    let partitioned_hashes = parse_and_split(
+        updated_state_hashes.clone(),
+        total_depth,
+        partition_size_exponent,
+        &TreeConfig::default(),
+    )?;
+
+    let mut updated_root_hashes: Vec<(u64, H::Node)> = Vec::new();
     for (partition_index, partition) in partitioned_hashes {
-        if partition.is_empty() {
-            continue;
+        let tree =
+            open_partition::<H>(store, partition_index, total_depth, partition_size_exponent)?;
+        let new_root = tree.sorted_root_after(partition, preimage.as_deref_mut())?;
+
+        updated_root_hashes.push((u64::from(partition_index), new_root));
+    }
+
+    let root_tree = open_partition::<H>(store, u32::MAX, total_depth, partition_size_exponent)?;
+    let new_root = root_tree.sorted_root_after(updated_root_hashes, preimage)?;
+
+    Ok(new_root.to_string())
+}
+
+/// Parses the updates and splits them into the partitions of the state tree,
+/// rejecting the leaves outside of the state tree.
+fn parse_and_split<N: MerkleNode>(
+    updated_state_hashes: HashMap<u64, String>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+    tree_config: &TreeConfig,
+) -> Result<Partitions<N>, MerkleError> {
+    check_partitioning(total_depth, partition_size_exponent)?;
+
+    let updated_state_hashes: HashMap<u64, N> = updated_state_hashes
+        .iter()
+        .map(|(idx, hash)| Ok((*idx, N::from_str(hash)?)))
+        .collect::<Result<HashMap<u64, N>, MerkleError>>()?;
+
+    let num_partitions = 1_u64 << (total_depth - partition_size_exponent);
+
+    let mut partitions: Partitions<N> = Vec::new();
+    for (partition_index, partition) in split_hashmap(
+        updated_state_hashes,
+        1_usize << partition_size_exponent,
+        tree_config,
+    ) {
+        match u32::try_from(partition_index) {
+            Ok(tree_index) if u64::from(tree_index) < num_partitions => {
+                partitions.push((tree_index, partition))
+            }
+            _ => {
+                return Err(MerkleError::IndexOutOfRange {
+                    level: partition_size_exponent,
+                    idx: partition_index as u64,
+                    depth: total_depth,
+                })
+            }
         }
+    }
 
-        let (_, new_root) = tree_partition_update(
-            partition,
-            &mut preimage_json,
-            partition_index as u32,
+    Ok(partitions)
+}
+
+/// Checks that the state tree can be split into partitions of depth `partition_size_exponent`.
+///
+/// Every partition is stored under its index as a `u32` and `u32::MAX` is the index of the root tree,
+/// so the root tree can have at most 2^31 leaves (partitions).
+fn check_partitioning(total_depth: u32, partition_size_exponent: u32) -> Result<(), MerkleError> {
+    if total_depth > MAX_TREE_DEPTH {
+        return Err(MerkleError::DepthOverflow {
+            depth: total_depth,
+            max_depth: MAX_TREE_DEPTH,
+        });
+    }
+
+    if partition_size_exponent > total_depth
+        || partition_size_exponent >= usize::BITS
+        || total_depth - partition_size_exponent >= u32::BITS
+    {
+        return Err(MerkleError::InvalidDepth {
             total_depth,
             partition_size_exponent,
+        });
+    }
+
+    Ok(())
+}
+
+fn update_partitions<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
+    tree_config: &TreeConfig,
+    partitioned_hashes: Partitions<H::Node>,
+    total_depth: u32,
+    partition_size_exponent: u32,
+) -> Result<(String, String, Preimage<H::Node>), MerkleError> {
+    // ? Every tree touched by the batch gets the version of the state tree it produces
+    // ? (so the trees always record their version, whatever the history limit of the config)
+    let version = store.read_version(u32::MAX)?;
+    let tree_config = &TreeConfig {
+        history_limit: tree_config.history_limit.max(1),
+        ..tree_config.clone()
+    };
+
+    // * UPDATE SPOT TREES  -------------------------------------------------------------------------------------
+    let num_updates: usize = partitioned_hashes.iter().map(|(_, p)| p.len()).sum();
+
+    // ? The partitions are independent trees, so they are loaded, updated and staged in parallel
+    // ? (each with its own preimage) and dropped as soon as they are staged
+    let update_partition = |(partition_index, partition): (u32, Vec<(u64, H::Node)>)| {
+        let tree =
+            open_partition::<H>(store, partition_index, total_depth, partition_size_exponent)?;
+
+        let mut partition_preimage = Preimage::new();
+        let (_, new_root, tree_update) = tree_partition_update(
+            tree.with_config(tree_config.clone()),
+            partition,
+            &mut partition_preimage,
+            version,
         )?;
+        store.stage_trees(&[tree_update])?;
+
+        Ok((partition_index, new_root, partition_preimage))
+    };
 
-        updated_root_hashes.insert(partition_index as u64, new_root);
+    // ? At most `partitions_in_flight` partition trees (and their preimages) are in memory at the same time
+    let mut updated_root_hashes: Vec<(u64, H::Node)> = Vec::new(); // the new roots of all tree partitions
+    let mut preimage: Preimage<H::Node> = Preimage::new();
+    let mut tree_indices: Vec<u32> = Vec::new();
+
+    let mut partitions = partitioned_hashes.into_iter().peekable();
+    while partitions.peek().is_some() {
+        let in_flight: Partitions<H::Node> = partitions
+            .by_ref()
+            .take(tree_config.partitions_in_flight)
+            .collect();
+
+        let updates: Vec<PartitionUpdate<H::Node>> = if tree_config.is_sequential(num_updates) {
+            in_flight
+                .into_iter()
+                .map(update_partition)
+                .collect::<Result<_, MerkleError>>()?
+        } else {
+            tree_config.install(|| {
+                in_flight
+                    .into_par_iter()
+                    .map(update_partition)
+                    .collect::<Result<_, MerkleError>>()
+            })?
+        };
+
+        // ? The preimages are merged in partition order, so the preimage does not depend on the waves
+        for (partition_index, new_root, partition_preimage) in updates {
+            updated_root_hashes.push((u64::from(partition_index), new_root));
+            tree_indices.push(partition_index);
+            preimage.merge(partition_preimage);
+        }
     }
 
     // ? use the newly generated roots to update the state tree
-    let (prev_spot_root, new_spot_root) = tree_partition_update(
+    let root_tree = open_partition::<H>(store, u32::MAX, total_depth, partition_size_exponent)?;
+    let (prev_spot_root, new_spot_root, tree_update) = tree_partition_update(
+        root_tree.with_config(tree_config.clone()),
         updated_root_hashes,
-        &mut preimage_json,
-        u32::MAX,
-        total_depth,
-        partition_size_exponent,
+        &mut preimage,
+        version,
     )?;
+    store.stage_trees(&[tree_update])?;
+    tree_indices.push(u32::MAX);
 
-    Ok((prev_spot_root, new_spot_root, preimage_json))
+    // ? Make all the updated trees visible at once
+    store.commit_trees(&tree_indices)?;
+
+    Ok((
+        prev_spot_root.to_string(),
+        new_spot_root.to_string(),
+        preimage,
+    ))
 }
 
-fn tree_partition_update(
-    updated_state_hashes: HashMap<u64, String>,
-    preimage_json: &mut Map<String, Value>,
+/// Applies the updates of one partition (or of the root tree) opened from the store
+fn tree_partition_update<H: MerkleHasher>(
+    mut batch_init_tree: Tree<H>,
+    updated_state_hashes: Vec<(u64, H::Node)>,
+    preimage: &mut Preimage<H::Node>,
+    version: u64,
+) -> Result<TreeTransition<H::Node>, MerkleError> {
+    batch_init_tree.version = version;
+
+    let prev_root = batch_init_tree.root;
+
+    batch_init_tree.sorted_transition_updates(updated_state_hashes, preimage)?;
+
+    let new_root = batch_init_tree.root;
+
+    // ? The updated nodes are written together with the other trees of the batch
+    Ok((prev_root, new_root, batch_init_tree.store_update()))
+}
+
+/// Opens the partition tree at `tree_index` (or the root tree if it is `u32::MAX`) in the store
+fn open_partition<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
     tree_index: u32,
     total_depth: u32,
     partition_size_exponent: u32,
-) -> Result<(String, String), Box<dyn Error>> {
+) -> Result<Tree<H>, MerkleError> {
+    check_partitioning(total_depth, partition_size_exponent)?;
+
     let shift = if tree_index == u32::MAX {
         partition_size_exponent
     } else {
@@ -77,50 +398,86 @@ fn tree_partition_update(
         partition_size_exponent
     };
 
-    let mut batch_init_tree = Tree::from_disk(tree_index, depth, shift)?;
+    Tree::<H>::from_store(store.clone(), tree_index, depth, shift)
+}
 
-    let prev_root = batch_init_tree.root.clone();
+/// The root of the state tree after the `update_trees` call that produced `version`
+/// (0 is the state tree before the first call).
+pub fn state_root_at<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
+    version: u64,
+    total_depth: u32,
+    partition_size_exponent: u32,
+) -> Result<String, MerkleError> {
+    let root_tree = open_partition::<H>(store, u32::MAX, total_depth, partition_size_exponent)?;
+
+    Ok(root_tree.root_at(version)?.to_string())
+}
 
-    // ? Store the current tree to disk as a backup
-    batch_init_tree.store_to_disk(tree_index)?;
+/// The merkle proof of a leaf of the state tree (of depth `total_depth`) after the `update_trees` call
+/// that produced `version`, made of the proof in its partition followed by the proof of the partition root.
+pub fn get_state_proof_at<H: MerkleHasher>(
+    store: &Arc<dyn NodeStore<H::Node>>,
+    version: u64,
+    leaf_idx: u64,
+    total_depth: u32,
+    partition_size_exponent: u32,
+) -> Result<MerkleProof<H>, MerkleError> {
+    let root_tree = open_partition::<H>(store, u32::MAX, total_depth, partition_size_exponent)?;
+    let root_proof = root_tree.get_proof_at(version, leaf_idx >> partition_size_exponent)?;
+
+    // ? The root proof only exists if the partition index fits in the root tree (below 2^31)
+    let partition_index = u32::try_from(leaf_idx >> partition_size_exponent).map_err(|_| {
+        MerkleError::IndexOutOfRange {
+            level: 0,
+            idx: leaf_idx,
+            depth: total_depth,
+        }
+    })?;
+    let partition =
+        open_partition::<H>(store, partition_index, total_depth, partition_size_exponent)?;
 
-    batch_init_tree.batch_transition_updates(&updated_state_hashes, preimage_json);
+    // ? A partition that was not touched after `version` is unchanged since then
+    let partition_leaf = leaf_idx & ((1 << partition_size_exponent) - 1);
+    let mut proof = partition.get_proof_at(version.min(partition.version), partition_leaf)?;
 
-    let new_root = batch_init_tree.root.clone();
+    proof.leaf_idx = leaf_idx;
+    proof.path.extend(root_proof.path);
+    proof.directions.extend(root_proof.directions);
+    proof.depth = total_depth;
 
-    Ok((prev_root, new_root))
+    return Ok(proof);
 }
 
 // * ================================================================================
 
-/// Splits a hashmap into submaps of size `chunk_size`.
-pub fn split_hashmap(
-    hashmap: HashMap<u64, String>,
+/// Splits a hashmap into submaps of size `chunk_size` as (submap_index, [(key % chunk_size, value)]).
+///
+/// Only the non-empty submaps are returned, sorted by submap index, and every submap is sorted by key
+/// (the entries are sorted in parallel as set in `config`).
+pub fn split_hashmap<N: Send>(
+    hashmap: HashMap<u64, N>,
     chunk_size: usize,
-) -> Vec<(usize, HashMap<u64, String>)> {
-    let max_key = *hashmap.keys().max().unwrap_or(&0);
-    let num_submaps = (max_key as usize + chunk_size) / chunk_size;
-
-    let submaps: Vec<(usize, HashMap<u64, String>)> = (0..num_submaps)
-        .into_par_iter()
-        .map(|submap_index| {
-            let submap: HashMap<u64, String> = hashmap
-                .iter()
-                .filter(|(key, _)| {
-                    let submap_start = if submap_index == 0 {
-                        0
-                    } else {
-                        submap_index * chunk_size
-                    };
-                    let submap_end = (submap_index + 1) * chunk_size;
-                    **key >= submap_start as u64 && **key < submap_end as u64
-                })
-                .map(|(key, value)| (key % chunk_size as u64, value.clone()))
-                .collect();
+    config: &TreeConfig,
+) -> Vec<(usize, Vec<(u64, N)>)> {
+    let mut entries: Vec<(u64, N)> = hashmap.into_iter().collect();
+    if config.is_sequential(entries.len()) {
+        entries.sort_unstable_by_key(|(key, _)| *key);
+    } else {
+        config.install(|| entries.par_sort_unstable_by_key(|(key, _)| *key));
+    }
 
-            (submap_index, submap)
-        })
-        .collect();
+    // ? The sorted entries of a submap are next to each other
+    let mut submaps: Vec<(usize, Vec<(u64, N)>)> = Vec::new();
+    for (key, value) in entries {
+        let submap_index = (key / chunk_size as u64) as usize;
+        let entry = (key % chunk_size as u64, value);
+
+        match submaps.last_mut() {
+            Some((last_index, submap)) if *last_index == submap_index => submap.push(entry),
+            _ => submaps.push((submap_index, vec![entry])),
+        }
+    }
 
     submaps
 }
@@ -1,93 +1,1509 @@
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
-    str::FromStr,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
 };
 
-use crate::Tree;
+use parking_lot::{const_mutex, const_rwlock, Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-pub fn _store_to_disk_inner(
-    leaf_nodes: &Vec<String>,
-    inner_nodes: &Vec<Vec<String>>,
-    root: &String,
-    depth: u32,
+use crate::{
+    utils::{
+        errors::MerkleError,
+        hashers::{Hash256, MerkleHasher, MerkleNode},
+        history::VersionRecord,
+        parallelization::TreeConfig,
+        tree_utils::{get_zero_hash, zero_hashes},
+    },
+    Tree,
+};
+
+/// The payload of a tree file: (leaf_nodes, inner_nodes, root) where only the non-zero nodes
+/// are stored as (index, 32 byte big-endian node) pairs sorted by index
+type EncodedTree = (Vec<(u64, [u8; 32])>, Vec<Vec<(u64, [u8; 32])>>, [u8; 32]);
+
+/// The legacy on-disk representation where every node was stored as a decimal string
+type LegacyEncodedTree = (Vec<String>, Vec<Vec<String>>, String, u32);
+
+/// A decoded legacy tree and the header it is rewritten with
+type MigratedTree<N> = (TreeHeader, DecodedTree<N>);
+
+/// (tree_index, path, tree) of a legacy file, where the tree is `None` for the empty files
+type LegacyFile<N> = (u32, PathBuf, Option<MigratedTree<N>>);
+
+/// (leaf_nodes, inner_nodes, root, depth) of a stored tree
+///
+/// The files don't depend on the hasher, so the trees that are only copied around
+/// (backups, compaction) are decoded with `Hash256` nodes, which keep the raw bytes.
+pub(crate) type DecodedTree<N> = (HashMap<u64, N>, Vec<HashMap<u64, N>>, N, u32);
+
+/// Where the trees are stored: every namespace gets its own `<base_path>/<namespace>/` folder
+/// (and `<base_path>/<namespace>_backup/` for the backups), so that several independent trees
+/// can share the same base path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageConfig {
+    pub base_path: PathBuf,
+    pub namespace: String,
+}
+
+impl StorageConfig {
+    pub fn new(base_path: impl Into<PathBuf>, namespace: impl Into<String>) -> StorageConfig {
+        StorageConfig {
+            base_path: base_path.into(),
+            namespace: namespace.into(),
+        }
+    }
+
+    /// The folder holding the trees of this namespace
+    pub fn tree_dir(&self) -> PathBuf {
+        self.base_path.join(&self.namespace)
+    }
+
+    /// The folder holding the backups of this namespace, one folder per batch named after
+    /// the version the batch produced (see `collect_garbage`)
+    pub fn backup_dir(&self) -> PathBuf {
+        self.base_path.join(self.namespace.clone() + "_backup")
+    }
+
+    /// The folder holding the backups of the batch that produced `version`
+    pub fn batch_backup_dir(&self, version: u64) -> PathBuf {
+        self.backup_dir().join(version.to_string())
+    }
+
+    fn tree_path(&self, tree_index: u32) -> PathBuf {
+        self.tree_dir().join(tree_index.to_string())
+    }
+
+    fn staged_path(&self, tree_index: u32) -> PathBuf {
+        self.tree_path(tree_index).with_extension(STAGED_EXTENSION)
+    }
+
+    fn delta_path(&self, tree_index: u32) -> PathBuf {
+        self.tree_path(tree_index).with_extension(DELTA_EXTENSION)
+    }
+
+    fn staged_delta_path(&self, tree_index: u32) -> PathBuf {
+        self.tree_path(tree_index)
+            .with_extension(STAGED_DELTA_EXTENSION)
+    }
+
+    fn history_path(&self, tree_index: u32) -> PathBuf {
+        self.tree_path(tree_index).with_extension(HISTORY_EXTENSION)
+    }
+
+    fn staged_history_path(&self, tree_index: u32) -> PathBuf {
+        self.tree_path(tree_index)
+            .with_extension(STAGED_HISTORY_EXTENSION)
+    }
+
+    fn backup_path(&self, version: u64, tree_index: u32) -> PathBuf {
+        self.batch_backup_dir(version).join(tree_index.to_string())
+    }
+
+    fn finalized_path(&self) -> PathBuf {
+        self.backup_dir().join(FINALIZED_FILE)
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.tree_dir().join(JOURNAL_FILE)
+    }
+}
+
+impl Default for StorageConfig {
+    /// The state tree at `./storage/merkle_trees/state_tree/`
+    fn default() -> Self {
+        StorageConfig::new("./storage/merkle_trees", "state_tree")
+    }
+}
+
+/// Written once all the trees of a batch are staged, it lists the changes that belong to the batch.
+/// The batch is committed as soon as the journal exists.
+const JOURNAL_FILE: &str = "journal";
+const STAGED_EXTENSION: &str = "staged";
+const DELTA_EXTENSION: &str = "delta";
+const STAGED_DELTA_EXTENSION: &str = "delta_staged";
+const HISTORY_EXTENSION: &str = "history";
+const STAGED_HISTORY_EXTENSION: &str = "history_staged";
+/// Marks a backed up tree that had not been stored yet
+const MISSING_EXTENSION: &str = "missing";
+const TMP_EXTENSION: &str = "tmp";
+/// Holds the last finalized version in the backup folder (see `mark_finalized`)
+const FINALIZED_FILE: &str = "finalized";
+
+// * FILE FORMAT * //
+
+/// The first bytes of every tree and delta file
+const MAGIC: [u8; 4] = *b"MRKT";
+/// The version of the on-disk format written by this crate
+pub const FORMAT_VERSION: u16 = 1;
+/// magic (4) | version (2) | file kind (1) | hasher id (1) | depth (4) | shift (4)
+const HEADER_LEN: usize = 16;
+/// payload length (8) | sha256 checksum of the payload (32)
+const FRAME_LEN: usize = 40;
+
+const TREE_FILE: u8 = 0;
+const DELTA_FILE: u8 = 1;
+const HISTORY_FILE: u8 = 2;
+
+/// Describes the tree stored in a file, written at the start of every tree and delta file.
+///
+/// A tree file is `header | payload length | checksum | payload`, a delta file is the header
+/// followed by any number of `payload length | checksum | payload` records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeHeader {
+    pub version: u16,
+    /// `MerkleHasher::ID` of the hash function the tree was built with
+    pub hasher_id: u8,
+    pub depth: u32,
+    pub shift: u32,
+}
+
+impl TreeHeader {
+    pub fn new<H: MerkleHasher>(depth: u32, shift: u32) -> TreeHeader {
+        TreeHeader {
+            version: FORMAT_VERSION,
+            hasher_id: H::ID,
+            depth,
+            shift,
+        }
+    }
+
+    fn encode(&self, kind: u8) -> [u8; HEADER_LEN] {
+        let mut buf = [0_u8; HEADER_LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6] = kind;
+        buf[7] = self.hasher_id;
+        buf[8..12].copy_from_slice(&self.depth.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.shift.to_le_bytes());
+
+        return buf;
+    }
+
+    fn decode(buf: &[u8], kind: u8, path: &Path) -> Result<TreeHeader, MerkleError> {
+        if buf.len() < HEADER_LEN || buf[..4] != MAGIC {
+            return Err(MerkleError::UnsupportedFormat(format!(
+                "{} has no format header (legacy files have to be upgraded with `migrate_storage`)",
+                path.display()
+            )));
+        }
+
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        if version != FORMAT_VERSION {
+            return Err(MerkleError::UnsupportedFormat(format!(
+                "{} has format version {} (supported: {})",
+                path.display(),
+                version,
+                FORMAT_VERSION
+            )));
+        }
+        if buf[6] != kind {
+            return Err(MerkleError::Corrupted(format!(
+                "{} has the wrong file kind {}",
+                path.display(),
+                buf[6]
+            )));
+        }
+
+        let mut depth = [0_u8; 4];
+        depth.copy_from_slice(&buf[8..12]);
+        let mut shift = [0_u8; 4];
+        shift.copy_from_slice(&buf[12..16]);
+
+        Ok(TreeHeader {
+            version,
+            hasher_id: buf[7],
+            depth: u32::from_le_bytes(depth),
+            shift: u32::from_le_bytes(shift),
+        })
+    }
+}
+
+/// Prefixes the payload with its length and checksum.
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&Sha256::digest(payload));
+    buf.extend_from_slice(payload);
+
+    return buf;
+}
+
+/// Reads the framed payload at the start of `buf`, returns it with the length of the frame.
+fn unframe<'a>(buf: &'a [u8], path: &Path) -> Result<(&'a [u8], usize), MerkleError> {
+    if buf.len() < FRAME_LEN {
+        return Err(MerkleError::Corrupted(format!(
+            "{} is truncated",
+            path.display()
+        )));
+    }
+
+    let mut len = [0_u8; 8];
+    len.copy_from_slice(&buf[..8]);
+    let len = u64::from_le_bytes(len);
+    if len > (buf.len() - FRAME_LEN) as u64 {
+        return Err(MerkleError::Corrupted(format!(
+            "{} is truncated",
+            path.display()
+        )));
+    }
+
+    let payload = &buf[FRAME_LEN..FRAME_LEN + len as usize];
+    if Sha256::digest(payload)[..] != buf[8..FRAME_LEN] {
+        return Err(MerkleError::Corrupted(format!(
+            "{} does not match its checksum",
+            path.display()
+        )));
+    }
+
+    Ok((payload, FRAME_LEN + len as usize))
+}
+
+/// A change to the files of a tree that is (re)applied from the journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum JournalEntry {
+    /// Replace the tree with its staged version and drop its deltas
+    Replace(u32),
+    /// Append the staged delta to the delta file of the tree, which was `len` bytes long before
+    Append { tree_index: u32, len: u64 },
+    /// Remove the tree and its deltas (its history is kept for the older versions)
+    Remove(u32),
+    /// Append the staged history records to the history of the tree, which was `len` bytes long before
+    AppendHistory { tree_index: u32, len: u64 },
+}
+
+/// The changed nodes of a tree as (level, idx, node), where `None` removes the node
+pub type NodeChanges<N> = [(u32, u64, Option<N>)];
+
+/// A delta record appended to the delta file of a tree: (nodes, root) where nodes are
+/// (level, idx, 32 byte big-endian node or `None` if the node was removed)
+type EncodedDelta = (Vec<(u32, u64, Option<[u8; 32]>)>, [u8; 32]);
+
+/// A record of the history file of a tree: (version, previous root, previous nodes) (see `VersionRecord`)
+type EncodedVersionRecord = (u64, [u8; 32], Vec<(u32, u64, Option<[u8; 32]>)>);
+
+/// The history records a tree keeps in memory and the version of the tree
+pub type TreeHistory<'a, N> = (&'a [VersionRecord<N>], u64);
+
+/// Replaces the stored tree (and its deltas) with the tree.
+///
+/// If `history` is given the history of the stored tree is brought to the version of the tree
+/// in the same commit (see `stage_tree_history`), otherwise it is left as it is.
+pub fn _store_to_disk_inner<N: MerkleNode>(
+    config: &StorageConfig,
+    header: &TreeHeader,
+    leaf_nodes: &HashMap<u64, N>,
+    inner_nodes: &Vec<HashMap<u64, N>>,
+    root: &N,
     tree_index: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let str: String = "./storage/merkle_trees/state_tree/".to_string() + &tree_index.to_string();
+    history: Option<TreeHistory<N>>,
+) -> Result<(), MerkleError> {
+    // ? The full tree replaces the stored tree together with its deltas in a single commit
+    _stage_to_disk_inner(config, header, leaf_nodes, inner_nodes, root, tree_index)?;
 
-    let path = Path::new(&str);
-    if !Path::new("./storage/merkle_trees/state_tree/").exists() {
-        fs::create_dir("./storage/merkle_trees/state_tree/")?;
+    let mut entries = vec![JournalEntry::Replace(tree_index)];
+    if let Some(history) = history {
+        entries.extend(stage_tree_history(config, header, history, tree_index)?);
     }
 
-    let mut file: File = File::create(path)?;
+    commit_journal(config, &entries)
+}
+
+/// Writes the tree next to its stored version without replacing it.
+/// The staged trees only become visible once the batch is committed with `commit_staged`.
+pub fn _stage_to_disk_inner<N: MerkleNode>(
+    config: &StorageConfig,
+    header: &TreeHeader,
+    leaf_nodes: &HashMap<u64, N>,
+    inner_nodes: &Vec<HashMap<u64, N>>,
+    root: &N,
+    tree_index: u32,
+) -> Result<(), MerkleError> {
+    let mut encoded = header.encode(TREE_FILE).to_vec();
+    encoded.extend(frame(&encode_tree(leaf_nodes, inner_nodes, root)?));
+
+    write_staged(&config.staged_path(tree_index), &encoded)
+}
+
+/// Appends only the changed nodes to the delta file of the tree instead of rewriting the whole tree
+/// (the history is handled like in `_store_to_disk_inner`).
+pub fn _store_delta_to_disk_inner<N: MerkleNode>(
+    config: &StorageConfig,
+    header: &TreeHeader,
+    nodes: &NodeChanges<N>,
+    root: &N,
+    tree_index: u32,
+    history: Option<TreeHistory<N>>,
+) -> Result<(), MerkleError> {
+    _stage_delta_to_disk_inner(config, header, nodes, root, tree_index)?;
+
+    let mut entries = vec![JournalEntry::Append {
+        tree_index,
+        len: file_len(&config.delta_path(tree_index))?,
+    }];
+    if let Some(history) = history {
+        entries.extend(stage_tree_history(config, header, history, tree_index)?);
+    }
+
+    commit_journal(config, &entries)
+}
+
+/// Stages the history records a tree kept in memory and returns the journal entry that writes them,
+/// `None` if the stored history is already up to date.
+///
+/// The records are appended if they continue the stored history. Otherwise (the tree dropped
+/// versions that were never written, or it is not the tree the history belongs to) they replace it,
+/// so the stored history never has a gap.
+fn stage_tree_history<N: MerkleNode>(
+    config: &StorageConfig,
+    header: &TreeHeader,
+    (records, version): TreeHistory<N>,
+    tree_index: u32,
+) -> Result<Option<JournalEntry>, MerkleError> {
+    let stored_version = read_version(config, tree_index)?;
+
+    let len = match records.first() {
+        None if version == stored_version => return Ok(None),
+        Some(record) if record.version == stored_version + 1 => {
+            file_len(&config.history_path(tree_index))?
+        }
+        _ => 0,
+    };
+
+    if records.is_empty() {
+        // ? An empty staged file removes the stored history (see `append_staged`)
+        write_staged(&config.staged_history_path(tree_index), &[])?;
+    } else {
+        _stage_history_inner(config, header, records, tree_index)?;
+    }
+
+    Ok(Some(JournalEntry::AppendHistory { tree_index, len }))
+}
+
+/// Writes the changed nodes of the tree as a delta without appending it to the stored tree.
+/// The staged deltas only become visible once the batch is committed with `commit_staged_deltas`.
+pub fn _stage_delta_to_disk_inner<N: MerkleNode>(
+    config: &StorageConfig,
+    header: &TreeHeader,
+    nodes: &NodeChanges<N>,
+    root: &N,
+    tree_index: u32,
+) -> Result<(), MerkleError> {
+    let nodes = nodes
+        .iter()
+        .map(|(level, idx, node)| (*level, *idx, node.map(|node| node.to_bytes_be())))
+        .collect::<Vec<(u32, u64, Option<[u8; 32]>)>>();
+
+    let encoded: Vec<u8> = bincode::serialize(&(nodes, root.to_bytes_be()))?;
+
+    // ? The header is only written when the delta file is created (see `apply_journal`)
+    let mut staged = header.encode(DELTA_FILE).to_vec();
+    staged.extend(frame(&encoded));
+
+    write_staged(&config.staged_delta_path(tree_index), &staged)
+}
+
+/// Writes the history records of the tree next to its history without appending them.
+/// They are appended together with the staged delta of the tree by `commit_staged_deltas`.
+pub fn _stage_history_inner<N: MerkleNode>(
+    config: &StorageConfig,
+    header: &TreeHeader,
+    records: &[VersionRecord<N>],
+    tree_index: u32,
+) -> Result<(), MerkleError> {
+    let mut staged = header.encode(HISTORY_FILE).to_vec();
+    for record in records {
+        let nodes = record
+            .prev_nodes
+            .iter()
+            .map(|(level, idx, node)| (*level, *idx, node.map(|node| node.to_bytes_be())))
+            .collect::<Vec<(u32, u64, Option<[u8; 32]>)>>();
+
+        let encoded: Vec<u8> =
+            bincode::serialize(&(record.version, record.prev_root.to_bytes_be(), nodes))?;
+        staged.extend(frame(&encoded));
+    }
+
+    write_staged(&config.staged_history_path(tree_index), &staged)
+}
+
+/// Removes the history records staged for the tree (by an earlier staging of the tree that was not committed).
+pub(crate) fn discard_staged_history(
+    config: &StorageConfig,
+    tree_index: u32,
+) -> Result<(), MerkleError> {
+    let path = config.staged_history_path(tree_index);
+    remove_if_exists(&path)?;
+    PENDING_FILES.lock().remove(&path);
+
+    Ok(())
+}
+
+/// Atomically replaces the stored trees with the staged trees at `tree_indices`.
+///
+/// Writing the journal is the commit point: if the process crashes before it exists none of the
+/// staged trees are visible, if it crashes after `recover_storage` finishes the commit.
+pub fn commit_staged(config: &StorageConfig, tree_indices: &[u32]) -> Result<(), MerkleError> {
+    let entries = tree_indices
+        .iter()
+        .map(|tree_index| JournalEntry::Replace(*tree_index))
+        .collect::<Vec<JournalEntry>>();
+
+    commit_journal(config, &entries)
+}
+
+/// Atomically appends the staged deltas (and staged history records) of the trees at `tree_indices`
+/// (see `commit_staged`).
+pub fn commit_staged_deltas(
+    config: &StorageConfig,
+    tree_indices: &[u32],
+) -> Result<(), MerkleError> {
+    let mut entries: Vec<JournalEntry> = Vec::new();
+    for tree_index in tree_indices {
+        entries.push(JournalEntry::Append {
+            tree_index: *tree_index,
+            len: file_len(&config.delta_path(*tree_index))?,
+        });
+
+        if config.staged_history_path(*tree_index).exists() {
+            entries.push(JournalEntry::AppendHistory {
+                tree_index: *tree_index,
+                len: file_len(&config.history_path(*tree_index))?,
+            });
+        }
+    }
+
+    commit_journal(config, &entries)
+}
+
+/// The length of the file, 0 if it does not exist
+fn file_len(path: &Path) -> Result<u64, MerkleError> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Rewrites the trees at `tree_indices` whose delta file grew larger than the tree itself,
+/// so that reading a tree never has to replay more deltas than necessary.
+pub fn compact_large_deltas(
+    config: &StorageConfig,
+    tree_indices: &[u32],
+) -> Result<(), MerkleError> {
+    for tree_index in tree_indices {
+        let delta_len = fs::metadata(config.delta_path(*tree_index)).map_or(0, |m| m.len());
+        let tree_len = fs::metadata(config.tree_path(*tree_index)).map_or(0, |m| m.len());
+        if delta_len <= tree_len {
+            continue;
+        }
+
+        if let Some((header, (leaves, inner_nodes, root, _))) =
+            read_stored_tree::<Hash256>(config, *tree_index)?
+        {
+            _store_to_disk_inner(
+                config,
+                &header,
+                &leaves,
+                &inner_nodes,
+                &root,
+                *tree_index,
+                None,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Brings the storage back to a consistent state after a crash.
+///
+/// A batch with a journal is rolled forward, the staged and temporary files of a batch
+/// that was never committed are removed. The files staged by a batch of this process that is
+/// still being written are kept (see `PENDING_FILES`).
+///
+/// Every read of the stored trees (`Tree::from_disk`, the `FileNodeStore`, the version and history queries)
+/// rolls a pending journal forward as well, so they never see a half applied batch.
+pub fn recover_storage(config: &StorageConfig) -> Result<(), MerkleError> {
+    let _guard = JOURNAL_LOCK.write();
+
+    let dir = config.tree_dir();
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    // ? The staged files referenced by the journal are moved in place before the others are removed
+    apply_pending_journal(config)?;
+
+    let pending_files = PENDING_FILES.lock();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let is_staged = extension == Some(STAGED_EXTENSION)
+            || extension == Some(STAGED_DELTA_EXTENSION)
+            || extension == Some(STAGED_HISTORY_EXTENSION)
+            || extension == Some(TMP_EXTENSION);
+        if is_staged && !pending_files.contains(&path) {
+            fs::remove_file(&path)?;
+        }
+    }
+    sync_dir(&dir)?;
+
+    Ok(())
+}
+
+fn commit_journal(config: &StorageConfig, entries: &[JournalEntry]) -> Result<(), MerkleError> {
+    // ? No reader can see the trees while the journal is applied
+    let _guard = JOURNAL_LOCK.write();
+
+    commit_journal_locked(config, entries)
+}
+
+/// `commit_journal` with `JOURNAL_LOCK` already held for writing
+fn commit_journal_locked(
+    config: &StorageConfig,
+    entries: &[JournalEntry],
+) -> Result<(), MerkleError> {
+    for entry in entries {
+        let staged_path = match entry {
+            JournalEntry::Replace(tree_index) => config.staged_path(*tree_index),
+            JournalEntry::Append { tree_index, .. } => config.staged_delta_path(*tree_index),
+            JournalEntry::AppendHistory { tree_index, .. } => {
+                config.staged_history_path(*tree_index)
+            }
+            JournalEntry::Remove(_) => continue,
+        };
+
+        if !staged_path.exists() {
+            return Err(MerkleError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} was not staged", staged_path.display()),
+            )));
+        }
+    }
+
+    let encoded: Vec<u8> = bincode::serialize(&entries.to_vec())?;
+    write_atomic(&config.journal_path(), &encoded)?;
+
+    apply_journal(config, entries)
+}
 
-    let leaves = leaf_nodes;
-    // .iter()
-    // .map(|x| ())
-    // .collect::<Vec<[u8; 32]>>();
+/// Held for writing while a journal is written and applied (or the staged files are recovered),
+/// and for reading while a tree file, its deltas or its history are read, so that a read never
+/// sees a half applied batch.
+static JOURNAL_LOCK: RwLock<()> = const_rwlock(());
+
+/// The staged files written by this process that were not committed yet.
+/// They belong to a batch that is still being written, so `recover_storage` keeps them.
+static PENDING_FILES: Mutex<BTreeSet<PathBuf>> = const_mutex(BTreeSet::new());
+
+/// Applies the journal left behind by a crash (if any), `JOURNAL_LOCK` has to be held for writing.
+fn apply_pending_journal(config: &StorageConfig) -> Result<(), MerkleError> {
+    let journal_path = config.journal_path();
+    if journal_path.exists() {
+        let buf = fs::read(&journal_path)?;
+        let entries: Vec<JournalEntry> = bincode::deserialize(&buf[..])?;
+
+        apply_journal(config, &entries)?;
+    }
+
+    Ok(())
+}
+
+/// Runs `read` on the committed trees: a journal left behind by a crash is applied first
+/// (the staged files are kept, they may belong to a batch that is still being written).
+fn read_committed<T>(
+    config: &StorageConfig,
+    read: impl FnOnce() -> Result<T, MerkleError>,
+) -> Result<T, MerkleError> {
+    let guard = JOURNAL_LOCK.read();
+    if !config.journal_path().exists() {
+        return read();
+    }
+    drop(guard);
+
+    {
+        let _guard = JOURNAL_LOCK.write();
+        apply_pending_journal(config)?;
+    }
+
+    let _guard = JOURNAL_LOCK.read();
+    read()
+}
+
+/// Applies the journal entries, this can be repeated any number of times after a crash.
+fn apply_journal(config: &StorageConfig, entries: &[JournalEntry]) -> Result<(), MerkleError> {
+    // ? Files that were already moved in place before a crash have no staged file anymore
+    for entry in entries {
+        match entry {
+            JournalEntry::Replace(tree_index) => {
+                let staged_path = config.staged_path(*tree_index);
+                if staged_path.exists() {
+                    fs::rename(&staged_path, config.tree_path(*tree_index))?;
+                }
+
+                // ? The staged tree already contains every delta
+                remove_if_exists(&config.delta_path(*tree_index))?;
+            }
+            JournalEntry::Append { tree_index, len } => append_staged(
+                &config.staged_delta_path(*tree_index),
+                &config.delta_path(*tree_index),
+                *len,
+            )?,
+            JournalEntry::AppendHistory { tree_index, len } => append_staged(
+                &config.staged_history_path(*tree_index),
+                &config.history_path(*tree_index),
+                *len,
+            )?,
+            JournalEntry::Remove(tree_index) => {
+                remove_if_exists(&config.tree_path(*tree_index))?;
+                remove_if_exists(&config.delta_path(*tree_index))?;
+            }
+        }
+    }
+    sync_dir(&config.tree_dir())?;
+
+    // ? The staged files of the journal are committed now
+    let mut pending_files = PENDING_FILES.lock();
+    for entry in entries {
+        let staged_path = match entry {
+            JournalEntry::Replace(tree_index) => config.staged_path(*tree_index),
+            JournalEntry::Append { tree_index, .. } => config.staged_delta_path(*tree_index),
+            JournalEntry::AppendHistory { tree_index, .. } => {
+                config.staged_history_path(*tree_index)
+            }
+            JournalEntry::Remove(_) => continue,
+        };
+        pending_files.remove(&staged_path);
+    }
+    drop(pending_files);
+
+    fs::remove_file(config.journal_path())?;
+    sync_dir(&config.tree_dir())?;
+
+    Ok(())
+}
+
+/// Appends the records of the staged file to the file at `path`, which was `len` bytes long before.
+///
+/// The header of the staged file is only kept if the file is created. An empty staged file
+/// only cuts the file back to `len` bytes (and removes it if `len` is 0).
+fn append_staged(staged_path: &Path, path: &Path, len: u64) -> Result<(), MerkleError> {
+    if !staged_path.exists() {
+        return Ok(());
+    }
+
+    let staged = fs::read(staged_path)?;
+    if staged.is_empty() && len == 0 {
+        remove_if_exists(path)?;
+        fs::remove_file(staged_path)?;
+        return Ok(());
+    }
+
+    // ? Cut off whatever a crashed attempt appended before appending the records again
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    file.set_len(len)?;
+    file.seek(SeekFrom::End(0))?;
+
+    if len == 0 {
+        file.write_all(&staged)?;
+    } else if !staged.is_empty() {
+        file.write_all(&staged[HEADER_LEN..])?;
+    }
+    file.sync_all()?;
+
+    fs::remove_file(staged_path)?;
+
+    Ok(())
+}
+
+fn encode_tree<N: MerkleNode>(
+    leaf_nodes: &HashMap<u64, N>,
+    inner_nodes: &Vec<HashMap<u64, N>>,
+    root: &N,
+) -> Result<Vec<u8>, MerkleError> {
+    let leaves = encode_nodes(leaf_nodes);
 
     let inner_nodes = inner_nodes
         .iter()
-        .map(|x| x.iter().map(|y| y.to_string()).collect::<Vec<String>>())
-        .collect::<Vec<Vec<String>>>();
+        .map(encode_nodes)
+        .collect::<Vec<Vec<(u64, [u8; 32])>>>();
+
+    let encoded: Vec<u8> = bincode::serialize(&(leaves, inner_nodes, root.to_bytes_be()))?;
+
+    Ok(encoded)
+}
+
+/// Writes the file and flushes it to the disk.
+fn write_synced(path: &Path, buf: &[u8]) -> Result<(), MerkleError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file: File = File::create(path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Writes a staged file, which is kept by `recover_storage` until it is committed.
+fn write_staged(path: &Path, buf: &[u8]) -> Result<(), MerkleError> {
+    PENDING_FILES.lock().insert(path.to_path_buf());
+
+    write_synced(path, buf)
+}
+
+/// Writes the file to a temporary path and renames it into place.
+fn write_atomic(path: &Path, buf: &[u8]) -> Result<(), MerkleError> {
+    let tmp_path = path.with_extension(TMP_EXTENSION);
+    write_synced(&tmp_path, buf)?;
+
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+
+    Ok(())
+}
 
-    let encoded: Vec<u8> =
-        bincode::serialize(&(leaves, inner_nodes, root.to_string(), depth)).unwrap();
+fn remove_if_exists(path: &Path) -> Result<(), MerkleError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
 
-    file.write_all(&encoded[..])?;
+/// Flushes the directory entries (created, renamed and removed files) to the disk.
+fn sync_dir(dir: &Path) -> Result<(), MerkleError> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
 
     Ok(())
 }
 
-pub fn _from_disk_inner(
+/// Reads the stored tree and checks that it was written for a tree with the same hasher, depth and shift
+/// and that its root matches its nodes.
+pub fn _from_disk_inner<H: MerkleHasher>(
+    config: &StorageConfig,
     tree_index: u32,
     depth: u32,
     shift: u32,
-) -> Result<Tree, Box<dyn std::error::Error>> {
-    let str = "./storage/merkle_trees/state_tree/";
-    let path_str = str.to_string() + &tree_index.to_string();
-    let path = Path::new(&path_str);
-
-    let open_res = File::open(path).ok();
-    if open_res.is_none() {
-        if Path::new(&str).exists() {
-            File::create(path)?;
-            return Ok(Tree::new(depth, shift));
-        } else {
-            fs::create_dir(&str)?;
-            File::create(path)?;
-            return Ok(Tree::new(depth, shift));
+) -> Result<Tree<H>, MerkleError> {
+    let (header, (leaves, inner_nodes, root, _)) =
+        match read_stored_tree::<H::Node>(config, tree_index)? {
+            Some(stored) => stored,
+            None => return Err(MerkleError::TreeNotFound(tree_index)),
+        };
+
+    let expected = TreeHeader::new::<H>(depth, shift);
+    if header != expected {
+        return Err(MerkleError::TreeMismatch(format!(
+            "tree {} is stored as {:?}, expected {:?}",
+            tree_index, header, expected
+        )));
+    }
+
+    let zero_hashes = zero_hashes::<H>(depth.saturating_add(shift))?;
+
+    // ? The root has to be the top node of the tree (which is not stored if the tree is empty)
+    let top_node = if depth == 0 {
+        leaves.get(&0)
+    } else {
+        inner_nodes[depth as usize - 1].get(&0)
+    };
+    if *top_node.unwrap_or(&zero_hashes[(depth + shift) as usize]) != root {
+        return Err(MerkleError::Corrupted(format!(
+            "the root of tree {} does not match its nodes",
+            tree_index
+        )));
+    }
+
+    // ? The history stays on disk, the older versions are read from there (see `Tree::get_proof_at`)
+    let version = read_version(config, tree_index)?;
+
+    Ok(Tree {
+        leaf_nodes: leaves,
+        inner_nodes,
+        root,
+        depth,
+        shift,
+        zero_hashes,
+        store: None,
+        storage: Some(config.clone()),
+        tree_index,
+        dirty_nodes: HashSet::new(),
+        version,
+        history: Vec::new(),
+        batch_prev_nodes: HashMap::new(),
+        config: TreeConfig::default(),
+        hasher: PhantomData,
+    })
+}
+
+/// Reads and decodes the stored tree at `tree_index` with its deltas applied, `None` if it has not been stored yet.
+pub(crate) fn read_tree_file<N: MerkleNode>(
+    config: &StorageConfig,
+    tree_index: u32,
+) -> Result<Option<DecodedTree<N>>, MerkleError> {
+    Ok(read_stored_tree(config, tree_index)?.map(|(_, tree)| tree))
+}
+
+fn read_stored_tree<N: MerkleNode>(
+    config: &StorageConfig,
+    tree_index: u32,
+) -> Result<Option<(TreeHeader, DecodedTree<N>)>, MerkleError> {
+    read_committed(config, || {
+        read_tree_from(
+            &config.tree_path(tree_index),
+            &config.delta_path(tree_index),
+        )
+    })
+}
+
+fn read_tree_from<N: MerkleNode>(
+    tree_path: &Path,
+    delta_path: &Path,
+) -> Result<Option<(TreeHeader, DecodedTree<N>)>, MerkleError> {
+    let mut tree = match read_if_exists(tree_path)? {
+        Some(buf) => {
+            let header = TreeHeader::decode(&buf, TREE_FILE, tree_path)?;
+            let (payload, len) = unframe(&buf[HEADER_LEN..], tree_path)?;
+            if HEADER_LEN + len != buf.len() {
+                return Err(MerkleError::Corrupted(format!(
+                    "{} has trailing bytes",
+                    tree_path.display()
+                )));
+            }
+
+            Some((header, decode_tree(payload, header.depth, tree_path)?))
         }
+        None => None,
+    };
+
+    let buf = match read_if_exists(delta_path)? {
+        Some(buf) => buf,
+        None => return Ok(tree),
+    };
+
+    let delta_header = TreeHeader::decode(&buf, DELTA_FILE, delta_path)?;
+    let (header, (leaves, inner_nodes, stored_root, _)) = tree.get_or_insert_with(|| {
+        let inner_nodes = vec![HashMap::new(); delta_header.depth as usize];
+        (
+            delta_header,
+            (HashMap::new(), inner_nodes, N::ZERO, delta_header.depth),
+        )
+    });
+    if *header != delta_header {
+        return Err(MerkleError::Corrupted(format!(
+            "{} does not belong to its tree",
+            delta_path.display()
+        )));
+    }
+
+    let mut offset = HEADER_LEN;
+    while offset < buf.len() {
+        let (payload, len) = unframe(&buf[offset..], delta_path)?;
+        let (nodes, root): EncodedDelta = bincode::deserialize(payload)?;
+
+        for (level, idx, node) in nodes {
+            check_stored_idx(level, idx, header.depth, delta_path)?;
+
+            let level_nodes = if level == 0 {
+                &mut *leaves
+            } else {
+                &mut inner_nodes[level as usize - 1]
+            };
+
+            match node {
+                Some(node) => level_nodes.insert(idx, decode_node(&node)?),
+                None => level_nodes.remove(&idx),
+            };
+        }
+        *stored_root = decode_node(&root)?;
+
+        offset += len;
+    }
+
+    Ok(tree)
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, MerkleError> {
+    let mut file: File = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
     };
 
-    let mut file: File = open_res.unwrap();
     let mut buf: Vec<u8> = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    Ok(Some(buf))
+}
+
+/// The history records of the tree with a version greater than `from_version`, sorted by version.
+pub(crate) fn read_history<N: MerkleNode>(
+    config: &StorageConfig,
+    tree_index: u32,
+    from_version: u64,
+) -> Result<Vec<VersionRecord<N>>, MerkleError> {
+    let path = config.history_path(tree_index);
+    let buf = match read_committed(config, || read_if_exists(&path))? {
+        Some(buf) => buf,
+        None => return Ok(Vec::new()),
+    };
+    TreeHeader::decode(&buf, HISTORY_FILE, &path)?;
+
+    let mut records: Vec<VersionRecord<N>> = Vec::new();
+    let mut offset = HEADER_LEN;
+    while offset < buf.len() {
+        let (payload, len) = unframe(&buf[offset..], &path)?;
+        offset += len;
+
+        let (version, prev_root, prev_nodes): EncodedVersionRecord = bincode::deserialize(payload)?;
+        if version <= from_version {
+            continue;
+        }
+
+        let prev_nodes = prev_nodes
+            .into_iter()
+            .map(|(level, idx, node)| Ok((level, idx, node.map(|n| decode_node(&n)).transpose()?)))
+            .collect::<Result<Vec<(u32, u64, Option<N>)>, MerkleError>>()?;
+
+        records.push(VersionRecord {
+            version,
+            prev_root: decode_node(&prev_root)?,
+            prev_nodes,
+        });
+    }
 
+    Ok(records)
+}
+
+/// The version of the last history record of the tree (0 if it has no history),
+/// only the last record is read and checked.
+pub(crate) fn read_version(config: &StorageConfig, tree_index: u32) -> Result<u64, MerkleError> {
+    read_committed(config, || {
+        read_version_from(&config.history_path(tree_index))
+    })
+}
+
+/// The version of the last record in the history file at `path` (0 if there is none)
+fn read_version_from(path: &Path) -> Result<u64, MerkleError> {
+    let mut file: File = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let file_len = file.metadata()?.len();
+
+    let mut header = [0_u8; HEADER_LEN];
+    file.read_exact(&mut header)
+        .map_err(|_| MerkleError::Corrupted(format!("{} is truncated", path.display())))?;
+    TreeHeader::decode(&header, HISTORY_FILE, path)?;
+
+    // ? Skip from one record to the next using their length prefix
+    let mut offset = HEADER_LEN as u64;
+    let mut last_offset: Option<u64> = None;
+    while offset < file_len {
+        let mut len = [0_u8; 8];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut len)
+            .map_err(|_| MerkleError::Corrupted(format!("{} is truncated", path.display())))?;
+
+        last_offset = Some(offset);
+        offset = offset
+            .saturating_add(FRAME_LEN as u64)
+            .saturating_add(u64::from_le_bytes(len));
+    }
+
+    let last_offset = match last_offset {
+        Some(last_offset) => last_offset,
+        None => return Ok(0),
+    };
+
+    let mut buf: Vec<u8> = Vec::new();
+    file.seek(SeekFrom::Start(last_offset))?;
     file.read_to_end(&mut buf)?;
 
-    let decoded: (Vec<String>, Vec<Vec<String>>, String, u32) = bincode::deserialize(&buf[..])?;
+    let (payload, _) = unframe(&buf, path)?;
+    let (version, _, _): EncodedVersionRecord = bincode::deserialize(payload)?;
+
+    Ok(version)
+}
+
+fn check_stored_idx(level: u32, idx: u64, depth: u32, path: &Path) -> Result<(), MerkleError> {
+    let level_depth = depth.checked_sub(level);
+    let in_range = match level_depth {
+        Some(level_depth) => level_depth >= 64 || idx >> level_depth == 0,
+        None => false,
+    };
+
+    if !in_range {
+        return Err(MerkleError::Corrupted(format!(
+            "{} contains the out of range node ({}, {})",
+            path.display(),
+            level,
+            idx
+        )));
+    }
+
+    Ok(())
+}
+
+/// Copies the stored tree at `tree_index` (with its deltas and history) to the backup folder
+/// of the batch that produces `version`.
+pub fn backup_tree(
+    config: &StorageConfig,
+    version: u64,
+    tree_index: u32,
+) -> Result<(), MerkleError> {
+    fs::create_dir_all(config.batch_backup_dir(version))?;
+
+    let path = config.tree_path(tree_index);
+    let delta_path = config.delta_path(tree_index);
+    let backup_path = config.backup_path(version, tree_index);
+
+    if path.exists() {
+        fs::copy(&path, &backup_path)?;
+    }
+    if delta_path.exists() {
+        fs::copy(&delta_path, backup_path.with_extension(DELTA_EXTENSION))?;
+    }
+    if config.history_path(tree_index).exists() {
+        fs::copy(
+            config.history_path(tree_index),
+            backup_path.with_extension(HISTORY_EXTENSION),
+        )?;
+    }
+
+    // ? Trees that have not been stored yet are removed again when the backup is restored
+    if !path.exists() && !delta_path.exists() {
+        File::create(backup_path.with_extension(MISSING_EXTENSION))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the backups of the batch that produces `version` (left over by a batch that failed before it was committed).
+pub fn clear_batch_backup(config: &StorageConfig, version: u64) -> Result<(), MerkleError> {
+    match fs::remove_dir_all(config.batch_backup_dir(version)) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removes the backups of all the batches from the backup folder.
+pub fn clear_backup(config: &StorageConfig) -> Result<(), MerkleError> {
+    for version in backup_versions(config)? {
+        clear_batch_backup(config, version)?;
+    }
+
+    Ok(())
+}
+
+/// The versions of the batches that have a backup, sorted
+fn backup_versions(config: &StorageConfig) -> Result<Vec<u64>, MerkleError> {
+    if !config.backup_dir().exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut versions: Vec<u64> = Vec::new();
+    for entry in fs::read_dir(config.backup_dir())? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        if let Some(version) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            versions.push(version);
+        }
+    }
+    versions.sort_unstable();
+
+    Ok(versions)
+}
+
+/// Copies every tree in the latest backup back to the state tree folder, rolling back all the trees
+/// touched by the last batch (in a single commit). The backup is removed afterwards, so calling it
+/// again rolls back the batch before (as long as its backup was not garbage collected).
+pub fn restore_backup(config: &StorageConfig) -> Result<(), MerkleError> {
+    let version = match backup_versions(config)?.pop() {
+        Some(version) => version,
+        None => return Ok(()),
+    };
+
+    let mut tree_indices: HashSet<u32> = HashSet::new();
+    for entry in fs::read_dir(config.batch_backup_dir(version))? {
+        let path = entry?.path();
+
+        let tree_index = path
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse().ok());
+        if let Some(tree_index) = tree_index {
+            tree_indices.insert(tree_index);
+        }
+    }
+
+    let mut entries: Vec<JournalEntry> = Vec::new();
+    for tree_index in tree_indices {
+        // ? The backed up deltas are folded into the restored tree
+        let backup_path = config.backup_path(version, tree_index);
+        let delta_path = backup_path.with_extension(DELTA_EXTENSION);
+
+        match read_tree_from::<Hash256>(&backup_path, &delta_path)? {
+            Some((header, (leaves, inner_nodes, root, _))) => {
+                _stage_to_disk_inner(config, &header, &leaves, &inner_nodes, &root, tree_index)?;
+                entries.push(JournalEntry::Replace(tree_index));
+            }
+            None => entries.push(JournalEntry::Remove(tree_index)),
+        }
+
+        // ? Drop the history records of the rolled back batch
+        write_staged(&config.staged_history_path(tree_index), &[])?;
+        entries.push(JournalEntry::AppendHistory {
+            tree_index,
+            len: file_len(&backup_path.with_extension(HISTORY_EXTENSION))?,
+        });
+    }
+
+    commit_journal(config, &entries)?;
+
+    clear_batch_backup(config, version)
+}
+
+/// Which batch backups `collect_garbage` keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep the backups of the last `n` batches
+    Batches(u64),
+    /// Keep the backups of the batches after the last finalized version (see `mark_finalized`)
+    Finalized,
+}
+
+/// What `collect_garbage` removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GarbageCollection {
+    /// The versions of the removed batch backups
+    pub removed_backups: Vec<u64>,
+    /// The indices of the removed partitions (their root was the zero hash)
+    pub removed_trees: Vec<u32>,
+    pub bytes_reclaimed: u64,
+}
+
+/// Marks all the batches up to `version` as final, their backups are no longer needed
+/// to roll them back and can be removed with `collect_garbage(config, Retention::Finalized)`.
+pub fn mark_finalized(config: &StorageConfig, version: u64) -> Result<(), MerkleError> {
+    write_atomic(&config.finalized_path(), &version.to_le_bytes())
+}
+
+/// The last version marked with `mark_finalized`, `None` if no batch was finalized yet
+pub fn finalized_version(config: &StorageConfig) -> Result<Option<u64>, MerkleError> {
+    let buf = match read_if_exists(&config.finalized_path())? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+
+    let version: [u8; 8] = buf[..].try_into().map_err(|_| {
+        MerkleError::Corrupted(format!("{} is invalid", config.finalized_path().display()))
+    })?;
+
+    Ok(Some(u64::from_le_bytes(version)))
+}
+
+/// Removes the batch backups outside of the retention window and the stored partitions
+/// whose root is the zero hash of their depth, and reports how many bytes were reclaimed.
+///
+/// Removed partitions read as empty trees through a `FileNodeStore` (their history is kept,
+/// so the older versions can still be queried). The root tree (`u32::MAX`) is never removed.
+pub fn collect_garbage<H: MerkleHasher>(
+    config: &StorageConfig,
+    retention: Retention,
+) -> Result<GarbageCollection, MerkleError> {
+    // ? Remove the staged files of the batches that were never committed
+    recover_storage(config)?;
+
+    let mut collection = GarbageCollection::default();
+
+    // * BACKUPS * //
+    let oldest_kept = match retention {
+        Retention::Batches(batches) => {
+            let latest = read_version(config, u32::MAX)?;
+            latest.saturating_sub(batches) + 1
+        }
+        Retention::Finalized => finalized_version(config)?.map_or(0, |version| version + 1),
+    };
+
+    for version in backup_versions(config)? {
+        if version >= oldest_kept {
+            continue;
+        }
+
+        collection.bytes_reclaimed += dir_size(&config.batch_backup_dir(version))?;
+        clear_batch_backup(config, version)?;
+        collection.removed_backups.push(version);
+    }
+
+    // * ZERO PARTITIONS * //
+    // ? No batch can be committed between finding a zero partition and removing it
+    let _guard = JOURNAL_LOCK.write();
+    apply_pending_journal(config)?;
+
+    let dir = config.tree_dir();
+    if !dir.exists() {
+        return Ok(collection);
+    }
+
+    let mut zero_trees: Vec<u32> = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+
+        let tree_index: u32 = match path.file_stem().and_then(|name| name.to_str()) {
+            Some(name) => match name.parse() {
+                Ok(tree_index) => tree_index,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if tree_index == u32::MAX || zero_trees.contains(&tree_index) {
+            continue;
+        }
+
+        let stored = read_tree_from::<H::Node>(
+            &config.tree_path(tree_index),
+            &config.delta_path(tree_index),
+        )?;
+        let (header, (_, _, root, _)) = match stored {
+            Some(stored) => stored,
+            None => continue,
+        };
+        if header.hasher_id != H::ID {
+            return Err(MerkleError::TreeMismatch(format!(
+                "tree {} was stored with hasher {}, expected {}",
+                tree_index,
+                header.hasher_id,
+                H::ID
+            )));
+        }
 
-    let leaves = decoded.0;
-    // .iter()
-    // .map(|x| String::from_bytes_be(x).unwrap())
-    // .collect();
+        if root == get_zero_hash::<H>(header.depth, header.shift)? {
+            zero_trees.push(tree_index);
+        }
+    }
+    zero_trees.sort_unstable();
+
+    for tree_index in zero_trees.iter() {
+        collection.bytes_reclaimed += file_len(&config.tree_path(*tree_index))?;
+        collection.bytes_reclaimed += file_len(&config.delta_path(*tree_index))?;
+    }
+
+    let entries = zero_trees
+        .iter()
+        .map(|tree_index| JournalEntry::Remove(*tree_index))
+        .collect::<Vec<JournalEntry>>();
+    if !entries.is_empty() {
+        commit_journal_locked(config, &entries)?;
+    }
+    collection.removed_trees = zero_trees;
+
+    Ok(collection)
+}
+
+/// The total size of the files in the folder (and its subfolders)
+fn dir_size(dir: &Path) -> Result<u64, MerkleError> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Upgrades the legacy tree files (written without a format header) in the folder of the config
+/// to the current format and returns the indices of the upgraded trees.
+///
+/// Legacy files don't record the shift, so like in `update_trees` the root tree (`u32::MAX`)
+/// gets `root_tree_shift` and every other tree a shift of 0.
+///
+/// Every legacy file is decoded before the first one is rewritten, so a file that can't be
+/// decoded fails the migration without touching the folder.
+pub fn migrate_storage<H: MerkleHasher>(
+    config: &StorageConfig,
+    root_tree_shift: u32,
+) -> Result<Vec<u32>, MerkleError> {
+    let dir = config.tree_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    // ? The empty files are removed instead of rewritten
+    let mut legacy_trees: Vec<LegacyFile<H::Node>> = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+
+        let tree_index: u32 = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => match name.parse() {
+                Ok(tree_index) => tree_index,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        let buf = fs::read(&path)?;
+        if buf.starts_with(&MAGIC) {
+            continue;
+        }
+
+        // ? Older versions created empty files for trees that were never stored
+        if buf.is_empty() {
+            legacy_trees.push((tree_index, path, None));
+            continue;
+        }
+
+        let (mut leaves, mut inner_nodes, root, depth) = decode_legacy_tree::<H::Node>(&buf)
+            .map_err(|err| {
+                MerkleError::Corrupted(format!("{} can't be migrated: {}", path.display(), err))
+            })?;
+        let shift = if tree_index == u32::MAX {
+            root_tree_shift
+        } else {
+            0
+        };
+
+        // ? Legacy files are dense, so drop the zero nodes to keep the tree sparse
+        let zero_hashes = zero_hashes::<H>(depth.saturating_add(shift))?;
+        leaves.retain(|_, leaf| *leaf != zero_hashes[shift as usize]);
+        for (i, level) in inner_nodes.iter_mut().enumerate() {
+            level.retain(|_, node| *node != zero_hashes[i + 1 + shift as usize]);
+        }
+
+        let header = TreeHeader::new::<H>(depth, shift);
+        legacy_trees.push((
+            tree_index,
+            path,
+            Some((header, (leaves, inner_nodes, root, depth))),
+        ));
+    }
+
+    let mut migrated: Vec<u32> = Vec::with_capacity(legacy_trees.len());
+    for (tree_index, path, tree) in legacy_trees {
+        match tree {
+            Some((header, (leaves, inner_nodes, root, _))) => _store_to_disk_inner(
+                config,
+                &header,
+                &leaves,
+                &inner_nodes,
+                &root,
+                tree_index,
+                None,
+            )?,
+            None => fs::remove_file(&path)?,
+        }
+
+        migrated.push(tree_index);
+    }
+
+    migrated.sort_unstable();
+
+    Ok(migrated)
+}
+
+fn encode_nodes<N: MerkleNode>(nodes: &HashMap<u64, N>) -> Vec<(u64, [u8; 32])> {
+    let mut encoded = nodes
+        .iter()
+        .map(|(idx, node)| (*idx, node.to_bytes_be()))
+        .collect::<Vec<(u64, [u8; 32])>>();
+    encoded.sort_unstable_by_key(|(idx, _)| *idx);
+
+    return encoded;
+}
+
+fn decode_nodes<N: MerkleNode>(nodes: &[(u64, [u8; 32])]) -> Result<HashMap<u64, N>, MerkleError> {
+    nodes
+        .iter()
+        .map(|(idx, node)| Ok((*idx, decode_node(node)?)))
+        .collect()
+}
+
+/// Decodes the payload of a tree file and checks that every node fits in a tree of depth `depth`.
+fn decode_tree<N: MerkleNode>(
+    payload: &[u8],
+    depth: u32,
+    path: &Path,
+) -> Result<DecodedTree<N>, MerkleError> {
+    let (leaves, inner_nodes, root): EncodedTree = bincode::deserialize(payload)?;
+
+    if inner_nodes.len() != depth as usize {
+        return Err(MerkleError::Corrupted(format!(
+            "{} has {} levels for a tree of depth {}",
+            path.display(),
+            inner_nodes.len() + 1,
+            depth
+        )));
+    }
+    for (level, nodes) in std::iter::once(&leaves)
+        .chain(inner_nodes.iter())
+        .enumerate()
+    {
+        for (idx, _) in nodes.iter() {
+            check_stored_idx(level as u32, *idx, depth, path)?;
+        }
+    }
+
+    let leaves = decode_nodes(&leaves)?;
+    let inner_nodes = inner_nodes
+        .iter()
+        .map(|nodes| decode_nodes(nodes))
+        .collect::<Result<Vec<HashMap<u64, N>>, _>>()?;
+
+    Ok((leaves, inner_nodes, decode_node(&root)?, depth))
+}
+
+/// Decodes a tree file written by older versions, where every node was stored as a decimal string.
+fn decode_legacy_tree<N: MerkleNode>(buf: &[u8]) -> Result<DecodedTree<N>, MerkleError> {
+    let decoded: LegacyEncodedTree = bincode::deserialize(buf)?;
+
+    let dense_to_sparse = |nodes: &Vec<String>| {
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| Ok((idx as u64, N::from_str(node)?)))
+            .collect::<Result<HashMap<u64, N>, MerkleError>>()
+    };
+
+    let leaves = dense_to_sparse(&decoded.0)?;
     let inner_nodes = decoded
         .1
         .iter()
-        .map(|x| {
-            x.iter()
-                .map(|y| String::from_str(y.as_str()).unwrap())
-                .collect::<Vec<String>>()
-        })
-        .collect::<Vec<Vec<String>>>();
+        .map(dense_to_sparse)
+        .collect::<Result<Vec<HashMap<u64, N>>, _>>()?;
 
-    Ok(Tree {
-        leaf_nodes: leaves,
-        inner_nodes,
-        root: String::from_str(&decoded.2.as_str()).unwrap(),
-        depth: decoded.3,
-        shift,
-    })
+    Ok((leaves, inner_nodes, N::from_str(&decoded.2)?, decoded.3))
+}
+
+pub(crate) fn decode_node<N: MerkleNode>(bytes: &[u8; 32]) -> Result<N, MerkleError> {
+    N::from_bytes_be(bytes)
+        .map_err(|_| MerkleError::Serialization("invalid node in stored tree".to_string()))
 }